@@ -1,6 +1,7 @@
 MEMORY
 {
-  FLASH(RX) : ORIGIN = 0x08000000, LENGTH = 32K
+  /* The last two pages are reserved for the config, see record.rs. */
+  FLASH(RX) : ORIGIN = 0x08000000, LENGTH = 28K
   RAM(WX) : ORIGIN = 0x20000000, LENGTH = 8K
   /* Note that SRAM2 is also mapped at 0x10000000. */
 }
@@ -24,7 +25,14 @@ SECTIONS
      *(SORT_BY_ALIGNMENT(.bss*))
      __bss_end = .;
      *(SORT_BY_ALIGNMENT(.noinit*))
+     . = ALIGN(4);
+     __stack_limit = .;
   } > RAM
+  /* Interned log strings, see log.rs.  Not loaded, and the addresses are
+     offsets into the section. */
+  .logstr 0 (INFO): {
+     KEEP(*(.logstr));
+  }
   .stack_sizes (INFO): {
      KEEP(*(.stack_sizes));
   }
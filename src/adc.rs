@@ -1,14 +1,26 @@
 
+use stm_common::vcell::{UCell, VCell};
 use stm32g030::Interrupt::ADC as INTERRUPT;
 
-/// Approx change in ADC cal number for 0.3V change in Vcc.  This is used to
-/// set the point for maximum PWM.
-pub const OVER3: u32 = 273;
-/// Approx change in ADC cal number for 0.3V change in Vcc.  This is used to
-/// set the point for minimum PWM.
-pub const UNDER3: u32 = 273;
+use crate::log::log;
 
-macro_rules! dbgln {($($tt: tt)*) => {if false {stm_common::dbgln!($($tt)*)}}}
+/// SMPR code for the sampling time at a clock, at least the 5µs needed by the
+/// temperature sensor.
+pub const fn smp(clk: u32) -> u8 {
+    // Sampling times, in half ADC clocks.
+    const HALF_CYCLES: [u32; 8] = [3, 7, 15, 25, 39, 79, 159, 321];
+    let need = clk / 100_000;
+    let mut i = 0;
+    while HALF_CYCLES[i] < need {
+        i += 1;
+    }
+    i as u8
+}
+
+/// Conversion results, temperature sensor then VREFINT.
+static SAMPLES: UCell<[u32; 2]> = UCell::new([0; _]);
+/// Index into SAMPLES of the next conversion.
+static INDEX: VCell<u8> = VCell::new(0);
 
 pub fn power_up() {
     let adc = unsafe {&*stm32g030::ADC::PTR};
@@ -24,19 +36,24 @@ pub fn power_up() {
 }
 
 pub fn start() {
-    if crate::CONFIG.clk > 250_000 {
-        // If we're running fast then waste some time waiting for the ADC
-        // power-up.
-        for _ in 0 .. crate::CONFIG.clk / 250_000 {
-            #[cfg(target_arch = "arm")]
-            cortex_m::asm::nop();
-        }
+    let clock = crate::cpu::clock();
+    // If we're running fast then waste some time waiting for the ADC
+    // power-up.
+    for _ in 0 .. clock.adc_delay {
+        #[cfg(target_arch = "arm")]
+        cortex_m::asm::nop();
     }
     let adc = unsafe {&*stm32g030::ADC::PTR};
-    adc.CCR.write(|w| w.VREFEN().set_bit());
-    adc.CHSELR_0().write(|w| w.CHSEL13().set_bit());
+    adc.CCR.write(|w| w.VREFEN().set_bit().TSEN().set_bit());
+    // Channels are converted in order, the temperature sensor then VREFINT.
+    // WAIT holds off the next conversion until we have read the result.
+    adc.CHSELR_0().write(|w| w.CHSEL12().set_bit().CHSEL13().set_bit());
+    adc.CFGR1.write(|w| w.WAIT().set_bit());
+    adc.SMPR.write(|w| w.SMP1().bits(clock.smp));
+    INDEX.write(0);
     adc.IER.write(
-        |w| w.EOSIE().set_bit().EOCALIE().set_bit().ADRDYIE().set_bit());
+        |w| w.EOCIE().set_bit().EOSIE().set_bit().EOCALIE().set_bit()
+            .ADRDYIE().set_bit());
     // Start the calibration....
     adc.CR.write(|w| w.ADVREGEN().set_bit().ADCAL().set_bit());
 
@@ -45,53 +62,70 @@ pub fn start() {
 }
 
 pub fn isr() {
+    let now = crate::trace::now();
     let adc = unsafe {&*stm32g030::ADC::PTR};
     let rcc = unsafe {&*stm32g030::RCC::PTR};
 
     let isr = adc.ISR.read();
-    dbgln!("ADC ISR {:#x}", isr.bits());
+    log!(ADC, "ADC ISR {:#x}", isr.bits());
     // Clear interrupts.
     adc.ISR.write(|w| w.set(isr.bits()));
     if isr.EOCAL().bit() {
-        dbgln!("Cal done, enable");
-        stm_common::dbgln!("{:#x}", adc.CALFACT.read().bits());
+        log!(ADC, "Cal done, enable");
+        log!(ADC, "CALFACT {:#x}", adc.CALFACT.read().bits());
         // Enable the ADC!!!
         adc.CR.write(|w| w.ADVREGEN().set_bit().ADEN().set_bit());
     }
     if isr.ADRDY().bit() {
         // Start a calibration.
-        dbgln!("Ready.  Start.");
+        log!(ADC, "Ready.  Start.");
         adc.CR.write(
             |w| w.ADVREGEN().set_bit().ADEN().set_bit().ADSTART().set_bit());
     }
+    if isr.EOC().bit() {
+        let index = INDEX.read() as usize;
+        let data = adc.DR.read().bits();
+        if index < 2 {
+            unsafe {SAMPLES.as_mut()[index] = data};
+        }
+        INDEX.write(index as u8 + 1);
+    }
     if isr.EOS().bit() {
-        dbgln!("Conv done, off");
+        crate::trace::record(crate::trace::Event::AdcEos, now, 0);
+        log!(ADC, "Conv done, off");
         // Turn off the ADC.
-        adc.CCR.write(|w| w.VREFEN().clear_bit());
+        adc.CCR.write(|w| w.VREFEN().clear_bit().TSEN().clear_bit());
         adc.CR.write(|w| w.ADDIS().set_bit());
         adc.CR.write(|w| w.bits(0));
-        // Get the result.
-        let counts = adc.DR.read().bits();
+        // Get the results.
+        let [temp, counts] = *SAMPLES;
         // Stop the ADC clock.
         rcc.APBENR2.modify(|_, w| w.ADCEN().clear_bit());
+        // The ADC LSBs, and the ISR timing.
+        let tim = unsafe {&*stm32g030::TIM3::ptr()};
         crate::random::RANDOM.stir(counts);
-        // Compare with flash config & convert to mV offset.
+        crate::random::RANDOM.stir(temp);
+        crate::random::RANDOM.stir(tim.CNT.read().bits());
         let config = crate::config::get();
-        let cal = unsafe{*(0x1fff75aa as *const u16)} as u32;
-        let top = cal + config.adc_over as u32;
-        let max = config.adc_max as u32;
-        let delta = if top > counts {(top - counts).min(max)} else {0};
-        let duty = config.calc_duty(delta);
+        let cal = crate::battery::vrefint_cal();
+        let mv = crate::battery::millivolts(cal, counts);
+        let battery = &crate::battery::BATTERY;
+        battery.update(mv);
+        battery.update_level(config);
+
+        crate::temp::TEMP.update(crate::temp::tenths(temp, mv));
+
+        // Regulate the brightness from the filtered Vdd, unless calibrating.
+        let duty = crate::calibrate::duty().unwrap_or_else(|| {
+            let duty = config.calc_duty(battery.millivolts());
+            let duty = crate::battery::adjust_duty(duty);
+            crate::temp::compensate(
+                duty, crate::temp::TEMP.tenths(), config.temp_comp)
+        });
         crate::pulse::set_duty(duty);
 
-        let delta = cal as i32 - counts as i32;
-        const SCALE_F: f64 = 3000.0 * 3000.0 / 1212.0 / 4096.0;
-        const SCALE: i32 = (SCALE_F * 65536.0 + 0.5) as i32;
-        const {assert!(SCALE > 65536)};
-        const {assert!(SCALE < 1000000)};
-        let offset = (delta * SCALE) >> 16;
         // Log the counts...
-        dbgln!("ADC {counts} {delta} {offset}");
+        log!(ADC, "ADC {} {} {}", counts, mv, temp);
     }
 }
 
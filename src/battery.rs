@@ -0,0 +1,193 @@
+//! Battery (Vdd) voltage, from the ADC VREFINT readings.
+
+use stm_common::vcell::VCell;
+
+use crate::console::Args;
+use crate::pendsv::{FIFTH, SECOND, hold_display};
+
+/// VREFINT_CAL, the VREFINT reading at 3.0V, measured in the factory.
+const VREFINT_CAL: *const u16 = 0x1fff75aa as _;
+
+/// Voltage at which VREFINT_CAL is measured.
+const CAL_MV: u32 = 3000;
+
+/// Log₂ of the IIR filter time constant, in samples.
+const FILTER_SHIFT: u32 = 3;
+
+/// Log₂ of the fixed point scaling of the filtered value.
+const FRAC_BITS: u32 = 4;
+
+/// Hysteresis for leaving the low battery state.
+const HYSTERESIS_MV: u32 = 100;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive_const(Default)]
+pub enum Level {
+    #[default]
+    Normal,
+    /// Battery low, run dimmer and show a warning.
+    Low,
+    /// Battery exhausted, shut down.  This is never left.
+    Critical,
+}
+
+#[derive_const(Default)]
+pub struct Battery {
+    /// Filtered voltage, mV << FRAC_BITS, zero before the first sample.
+    filtered: VCell<u32>,
+    /// Minimum and maximum of the filtered voltage.
+    min: VCell<u16>,
+    max: VCell<u16>,
+    level: VCell<Level>,
+}
+
+pub static BATTERY: Battery = Battery::default();
+
+/// The VREFINT calibration, from the config if calibrated, else the factory
+/// value.
+pub fn vrefint_cal() -> u32 {
+    match crate::config::get().vref_cal {
+        0 => unsafe {*VREFINT_CAL as u32},
+        cal => cal as u32,
+    }
+}
+
+/// Convert a VREFINT reading to Vdd in mV.
+pub const fn millivolts(cal: u32, counts: u32) -> u32 {
+    if counts == 0 {
+        return 0;
+    }
+    (CAL_MV * cal + counts / 2) / counts
+}
+
+impl Battery {
+    /// Add a sample, called from the ADC ISR.
+    pub fn update(&self, mv: u32) {
+        let sample = mv << FRAC_BITS;
+        let old = self.filtered.read();
+        let filtered = if old == 0 {sample}
+            else {old - (old >> FILTER_SHIFT) + (sample >> FILTER_SHIFT)};
+        self.filtered.write(filtered);
+
+        let mv = self.millivolts() as u16;
+        if self.min.read() == 0 || mv < self.min.read() {
+            self.min.write(mv);
+        }
+        if mv > self.max.read() {
+            self.max.write(mv);
+        }
+    }
+
+    /// Update the battery level from the filtered voltage and the board
+    /// thresholds.
+    pub fn update_level(&self, config: &crate::config::Config) {
+        let level = self.level.read().next(
+            self.millivolts(), config.low_mv as u32, config.critical_mv as u32);
+        self.level.write(level);
+    }
+
+    pub fn level(&self) -> Level {self.level.read()}
+
+    /// Filtered Vdd in mV, or zero if there has been no reading yet.
+    pub fn millivolts(&self) -> u32 {
+        self.filtered.read() + (1 << FRAC_BITS - 1) >> FRAC_BITS
+    }
+
+    /// Minimum filtered Vdd since boot.
+    pub fn min(&self) -> u32 {self.min.read() as u32}
+
+    /// Maximum filtered Vdd since boot.
+    pub fn max(&self) -> u32 {self.max.read() as u32}
+}
+
+impl Level {
+    /// State transition for a new reading.
+    pub const fn next(self, mv: u32, low: u32, critical: u32) -> Level {
+        match self {
+            _ if mv == 0 => self,       // No reading yet.
+            Level::Critical => Level::Critical,
+            _ if mv <= critical => Level::Critical,
+            Level::Normal if mv < low => Level::Low,
+            Level::Low if mv >= low + HYSTERESIS_MV => Level::Normal,
+            _ => self,
+        }
+    }
+}
+
+/// Reduce the duty cycle when the battery is low.
+pub fn adjust_duty(duty: u32) -> u32 {
+    match BATTERY.level() {
+        Level::Normal => duty,
+        _ => duty - duty / 4,
+    }
+}
+
+/// Called between shows.  Shut down if the battery is exhausted, and flash
+/// the battery glyph if it is low.
+pub fn check(count: u32) {
+    match BATTERY.level() {
+        Level::Normal => (),
+        Level::Low => if count % 4 == 0 {
+            for _ in 0 .. 3 {
+                hold_display(crate::chars::BATTERY, SECOND);
+                hold_display(0, FIFTH);
+            }
+        },
+        Level::Critical => crate::power::shutdown(),
+    }
+}
+
+/// Console: `bat`.
+pub fn cmd_bat(_: &mut Args) {
+    stm_common::dbgln!("bat {}mV min {} max {} {:?}", BATTERY.millivolts(),
+                       BATTERY.min(), BATTERY.max(), BATTERY.level());
+}
+
+#[test]
+fn conversion() {
+    assert_eq!(millivolts(1655, 1655), 3000);
+    assert_eq!(millivolts(1655, 1504), 3301);
+    assert_eq!(millivolts(1655, 2069), 2400);
+    assert_eq!(millivolts(1655, 0), 0);
+}
+
+#[test]
+fn filter() {
+    let battery = Battery::default();
+    assert_eq!(battery.millivolts(), 0);
+    battery.update(3000);
+    assert_eq!(battery.millivolts(), 3000);
+    // Single outliers are damped...
+    battery.update(2200);
+    assert_eq!(battery.millivolts(), 2900);
+    // ...and steady values are tracked.
+    for _ in 0 .. 100 {
+        battery.update(2800);
+    }
+    assert_eq!(battery.millivolts(), 2800);
+    assert_eq!(battery.min(), 2800);
+    assert_eq!(battery.max(), 3000);
+}
+
+#[test]
+fn levels() {
+    // A slowly discharging battery, with ±60mV of ripple.
+    let trace = (0 .. 1000).map(|i| 3000 - i - if i % 2 == 0 {60} else {0});
+    let mut level = Level::Normal;
+    let mut changes = Vec::new();
+    for mv in trace {
+        let next = level.next(mv, 2600, 2300);
+        if next != level {
+            changes.push((mv, next));
+        }
+        level = next;
+    }
+    assert_eq!(changes, [(2598, Level::Low), (2300, Level::Critical)]);
+
+    // Recovery needs the hysteresis.
+    assert_eq!(Level::Low.next(2650, 2600, 2300), Level::Low);
+    assert_eq!(Level::Low.next(2700, 2600, 2300), Level::Normal);
+    // Critical is sticky, and no reading changes nothing.
+    assert_eq!(Level::Critical.next(3300, 2600, 2300), Level::Critical);
+    assert_eq!(Level::Low.next(0, 2600, 2300), Level::Low);
+}
@@ -1,35 +1,57 @@
 #![cfg_attr(target_os = "none", no_std)]
 #![cfg_attr(target_os = "none", no_main)]
 
-// For link_llvm_intrinsics.
-#![allow(internal_features)]
 // I value sane syntax over appeasing the Rust Gods.
 #![allow(unpredictable_function_pointer_comparisons)]
 // We do lots of const.
 #![feature(derive_const)]
 #![feature(const_cmp, const_convert, const_default, const_trait_impl)]
-// For frameaddress() in the crash handler.
-#![feature(link_llvm_intrinsics)]
+// For the naked fault handler entry.
+#![feature(naked_functions_rustic_abi)]
 
-use crate::marque::{marque_string, rmarque_string};
+use crate::marque::rmarque_string;
 use crate::pendsv::{FIFTH, SECOND, animate, hold_display};
 
 mod adc;
+mod battery;
+mod boot;
+mod calibrate;
 mod chars;
+mod clock;
 mod cpu;
 mod config;
+mod crash;
+mod console;
 mod debug;
+mod fault;
+mod flash;
 mod leds;
+mod log;
 mod marque;
+mod messages;
+mod model;
 mod pendsv;
+mod power;
 mod pulse;
 mod random;
+mod record;
+mod rtc;
+mod schedule;
+mod season;
+mod select;
+mod stack;
+mod temp;
+mod trace;
+mod watchdog;
 
 /// Flag for global enable/disable of debugging.
 const DEBUG_ENABLE: bool = !CONFIG.no_debug;
 
+/// The flash power down and sleep clock gating are on, see `power`.
 const CONFIG: cpu::Config =
-    *cpu::Config::new(250_000).adc().no_debug().pendsv().pulse();
+    *cpu::Config::new(250_000).adc().crash_blink().fast_clock(16_000_000)
+    .flash_powerdown().no_debug().pendsv().pulse().rtc().sleep_clocks()
+    .watchdog();
 
 /// Entry point used by the dbg! and dbgln! macros.
 fn debug_fmt(fmt: core::fmt::Arguments) {
@@ -123,43 +145,47 @@ fn run() -> ! {
             demo();
         }
     }
-    if false {
-        loop { // Pattern to test LEDs.
-            let mut d = 0x3f;
-            while d != 0 {
-                hold_display(d, 2 * FIFTH);
-                d <<= 8;
-            }
-        }
+    if boot::safe() {
+        boot::safe_mode();
     }
 
+    schedule::check();
+    crash::show();
     blink_in();
     hold_display(0, 1);
 
+    let mut season = season::active();
+    let mut today = rtc::read().days();
     let mut count = 0u32;
     loop {
+        schedule::check();
+        calibrate::run();
+        let now = rtc::read().days();
+        if now != today {
+            today = now;
+            season = season::active();
+        }
         count = count.saturating_add(1);
+        if count == 10 {
+            boot::stable();
+        }
+        battery::check(count);
         // Probability of exception ramps from 0 at <5 to ⅔ at 25.
         let normal = count <= 5
             || random::RANDOM.random_n(30) >= count.min(25) - 5;
 
         if normal {
-            const STR: &[u8] = &chars::map_str(b"MERRY XMAS ");
-            marque_string(&mut 0, STR, FIFTH);
+            if !messages::show_main() {
+                season.show_main();
+            }
             hold_display(0, 2);
         }
         else {
             blink_in();
             cycles();
             hold_display(0, FIFTH);
-            match random::RANDOM.random_n(7) {
-                0 => nice1(),
-                1 => nice2(),
-                2 => nice3(),
-                3 => nice4(),
-                4 => nice5(),
-                5 => naughty1(),
-                6|_ => naughty2(),
+            if !messages::show_exception() {
+                season.show_exception();
             }
             finish();
         }
@@ -183,6 +209,8 @@ fn main() -> ! {
     else {
         debug::init();
     }
+    crash::report();
+    boot::report();
 
     for i in 0 .. 4 {
         let gpio = leds::gpio(i);
@@ -192,8 +220,11 @@ fn main() -> ! {
         gpio.MODER.modify(|r, w| w.bits(r.bits() & !(bit2 * 2) | bit2));
     }
 
+    rtc::init();
+    schedule::init();
     pendsv::init();
     pulse::init();
+    watchdog::init();
 
     run();
 }
@@ -0,0 +1,260 @@
+//! Reset cause and boot statistics.
+//!
+//! The RCC reset flags are read and cleared at boot, and the count for the
+//! cause is bumped, see `record::BootCounts`.  A software reset with a crash
+//! pending, see `crash`, counts as a crash.
+//!
+//! The counts are kept over resets in `.noinit` RAM, and only written to
+//! flash when the cause changes, or every `BATCH` boots, so that a reset loop
+//! doesn't wear the flash, or stall in a page erase at each boot.  A power
+//! cycle loses up to `BATCH - 1` boots.
+//!
+//! Crashes and watchdog resets in a row are counted in `.noinit` RAM, and
+//! the count is cleared once the show has run for a while.  After
+//! `CRASH_LOOP` of them, we go to a safe mode that just shows an LED test
+//! pattern, and stop writing the flash, until the next power on or `boot
+//! clear`.
+
+use stm_common::vcell::{UCell, VCell};
+
+use crate::console::Args;
+use crate::pendsv::FIFTH;
+use crate::record::{BOOT_VERSION, BootCounts, CAUSES, KIND_BOOT, Store,
+                    crc32};
+
+/// Number of crashes in a row that gives safe mode.
+const CRASH_LOOP: u32 = 3;
+
+/// Most boots with the same cause between flash writes.
+const BATCH: u8 = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Cause {
+    /// Power on, or brown out.
+    Power,
+    /// The NRST pin.
+    Pin,
+    Soft,
+    /// A software reset after a fault or panic.
+    Crash,
+    Iwdg,
+    Wwdg,
+    /// Entering STANDBY or SHUTDOWN while disabled by the option bytes.
+    LowPower,
+    /// Option byte loading.  `watchdog::init` does that once on a new
+    /// device, to freeze the IWDG in STOP and STANDBY, and the boot is
+    /// counted, but not as a crash.
+    Option,
+}
+
+/// RCC CSR reset flags.
+const LPWRRSTF: u32 = 1 << 31;
+const WWDGRSTF: u32 = 1 << 30;
+const IWDGRSTF: u32 = 1 << 29;
+const SFTRSTF : u32 = 1 << 28;
+const PWRRSTF : u32 = 1 << 27;
+const PINRSTF : u32 = 1 << 26;
+const OBLRSTF : u32 = 1 << 25;
+
+/// The reset cause from the CSR flags.  The internal resets also pull NRST,
+/// so the pin flag comes last.
+pub const fn cause(csr: u32, crashed: bool) -> Cause {
+    if csr & PWRRSTF != 0 {
+        Cause::Power
+    }
+    else if csr & LPWRRSTF != 0 {
+        Cause::LowPower
+    }
+    else if csr & WWDGRSTF != 0 {
+        Cause::Wwdg
+    }
+    else if csr & IWDGRSTF != 0 {
+        Cause::Iwdg
+    }
+    else if csr & SFTRSTF != 0 {
+        if crashed {Cause::Crash} else {Cause::Soft}
+    }
+    else if csr & OBLRSTF != 0 {
+        Cause::Option
+    }
+    else {
+        Cause::Pin
+    }
+}
+
+/// Update the count of crashes in a row for a reset.
+pub const fn crash_loop(loops: u32, cause: Cause) -> u32 {
+    match cause {
+        Cause::Crash | Cause::Iwdg | Cause::Wwdg => loops.saturating_add(1),
+        Cause::Power => 0,
+        _ => loops,
+    }
+}
+
+const LOOP_MAGIC: u32 = 0xb007_10f5;
+
+/// Crashes in a row, and the same complemented, as `.noinit` RAM is random
+/// after power on.
+#[unsafe(link_section = ".noinit")]
+static LOOPS: UCell<[u32; 3]> = UCell::new([0; _]);
+
+static CAUSE: VCell<Cause> = VCell::new(Cause::Power);
+
+/// The boot counts, including those not yet in flash.
+#[derive(Clone, Copy)]
+struct Kept {
+    counts: BootCounts,
+    /// Cause of the previous boot.
+    last: u8,
+    /// Boots since the counts were last written.
+    unsaved: u8,
+    check: u32,
+}
+
+#[unsafe(link_section = ".noinit")]
+static KEPT: UCell<Kept> = UCell::new(Kept {
+    counts: BootCounts([0; _]), last: 0, unsaved: 0, check: 0});
+
+impl Kept {
+    fn checksum(&self) -> u32 {
+        crc32(LOOP_MAGIC, &self.counts.encode()) ^ (self.last as u32) << 8
+            ^ self.unsaved as u32
+    }
+    /// The kept counts, if they survived the reset.
+    fn get() -> Option<Kept> {
+        let kept = *KEPT.as_ref();
+        if kept.check == kept.checksum() {Some(kept)} else {None}
+    }
+    fn set(mut self) {
+        self.check = self.checksum();
+        *unsafe {KEPT.as_mut()} = self;
+    }
+}
+
+fn counts() -> BootCounts {
+    Kept::get().map_or(BootCounts([0; _]), |k| k.counts)
+}
+
+fn loops() -> u32 {
+    let [magic, loops, check] = *LOOPS.as_ref();
+    if magic == LOOP_MAGIC && check == !loops {loops} else {0}
+}
+
+fn set_loops(loops: u32) {
+    *unsafe {LOOPS.as_mut()} = [LOOP_MAGIC, loops, !loops];
+}
+
+/// Are we in safe mode?
+pub fn safe() -> bool {loops() >= CRASH_LOOP}
+
+/// Read and clear the reset flags, and count the boot.  Called from
+/// `cpu::init`, after the config is loaded.
+pub fn init() {
+    let rcc = unsafe {&*stm32g030::RCC::ptr()};
+    let pwr = unsafe {&*stm32g030::PWR::ptr()};
+    let csr = rcc.CSR.read().bits();
+    rcc.CSR.modify(|_, w| w.RMVF().set_bit());
+
+    let crashed = crate::crash::last().is_some_and(|c| c.pending);
+    let cause = cause(csr, crashed);
+
+    // A watchdog that isn't frozen in STANDBY woke us from `power::shutdown`.
+    // It is off now, so go back to STANDBY for good.
+    rcc.APBENR1.modify(|_, w| w.PWREN().set_bit());
+    if cause == Cause::Iwdg && pwr.SR1.read().SBF().bit() {
+        crate::power::shutdown();
+    }
+
+    CAUSE.write(cause);
+    set_loops(crash_loop(loops(), cause));
+
+    let kept = Kept::get();
+    let mut counts = kept.map_or_else(|| {
+        let store = crate::flash::store();
+        let page = store.page(crate::record::active(store));
+        crate::record::latest(page, KIND_BOOT)
+            .map_or(BootCounts([0; _]), |r| BootCounts::decode(r.payload))
+    }, |k| k.counts);
+    let count = &mut counts.0[cause as usize];
+    *count = count.saturating_add(1);
+    let unsaved = kept.map_or(0, |k| k.unsaved).saturating_add(1);
+    let changed = kept.is_none_or(|k| k.last != cause as u8);
+    let mut kept = Kept {counts, last: cause as u8, unsaved, check: 0};
+
+    // Don't wear the flash in a crash loop.
+    if !safe() && (changed || unsaved >= BATCH) && save(&counts).is_ok() {
+        kept.unsaved = 0;
+    }
+    kept.set();
+}
+
+fn save(counts: &BootCounts) -> Result<(), crate::record::Error> {
+    crate::flash::unlocked(|flash| crate::record::append(
+        flash, KIND_BOOT, BOOT_VERSION, &counts.encode()))
+}
+
+/// The show has run for a while, so we are not in a crash loop.
+pub fn stable() {
+    if loops() != 0 && !safe() {
+        set_loops(0);
+    }
+}
+
+/// Report the reset cause on the console, called at boot.
+pub fn report() {
+    stm_common::dbgln!("Boot {:?}{}", CAUSE.read(),
+                       if safe() {", safe mode"} else {""});
+}
+
+/// Safe mode, just step a test pattern through the LED rows.
+pub fn safe_mode() -> ! {
+    loop {
+        let mut d = 0x3f;
+        while d != 0 {
+            crate::pendsv::hold_display(d, 2 * FIFTH);
+            d <<= 8;
+        }
+    }
+}
+
+/// Console: `boot [clear]`, the reset cause and boot counts.  `clear` zeros
+/// the counts and leaves safe mode on the next reset.
+pub fn cmd_boot(args: &mut Args) {
+    match args.word() {
+        b"" => (),
+        b"clear" => {
+            set_loops(0);
+            let counts = BootCounts([0; _]);
+            Kept {counts, last: CAUSE.read() as u8, unsaved: 0, check: 0}.set();
+            stm_common::dbgln!("boot clear {:?}", save(&counts));
+            return;
+        }
+        _ => {
+            stm_common::dbgln!("boot [clear]");
+            return;
+        }
+    }
+    stm_common::dbgln!("boot {:?}, {} crashes in a row{}", CAUSE.read(),
+                       loops(), if safe() {", safe mode"} else {""});
+    for (name, count) in CAUSES.iter().zip(counts().0) {
+        stm_common::dbgln!("  {name:8} {count}");
+    }
+}
+
+#[test]
+fn causes() {
+    assert_eq!(cause(PWRRSTF | PINRSTF, false), Cause::Power);
+    assert_eq!(cause(PINRSTF, true), Cause::Pin);
+    assert_eq!(cause(SFTRSTF | PINRSTF, false), Cause::Soft);
+    assert_eq!(cause(SFTRSTF | PINRSTF, true), Cause::Crash);
+    assert_eq!(cause(IWDGRSTF | PINRSTF, false), Cause::Iwdg);
+    assert_eq!(cause(OBLRSTF | PINRSTF, false), Cause::Option);
+    assert_eq!(CAUSES[Cause::Option as usize], "option");
+
+    let mut loops = 0;
+    for cause in [Cause::Crash, Cause::Pin, Cause::Iwdg, Cause::Crash] {
+        loops = crash_loop(loops, cause);
+    }
+    assert_eq!(loops, CRASH_LOOP);
+    assert_eq!(crash_loop(loops, Cause::Power), 0);
+}
@@ -0,0 +1,111 @@
+//! Brightness calibration from the console.
+//!
+//! `cal` shows a test pattern, and overrides the regulated duty with a fixed
+//! level.  `cal +` and `cal -` step the level, and `cal ok` takes the current
+//! level as the brightness to regulate to at any supply, and saves it.
+//!
+//! With a known bench supply, `cal vdd <mV>` corrects the VREFINT
+//! calibration so that the battery voltage, and hence the regulation, is
+//! accurate.
+
+use stm_common::vcell::VCell;
+
+use crate::config::{CONFIG, DUTY_MAX, PWM_MIN};
+use crate::console::Args;
+use crate::pendsv::{FIFTH, hold_display};
+
+/// Test pattern, all LEDs on.
+const PATTERN: u64 = 0x3f3f3f3f3f3f;
+
+/// Number of duty levels, each 25% above the last.
+const LEVELS: u8 = 15;
+
+/// Current level plus one, zero when not calibrating.
+static LEVEL: VCell<u8> = VCell::new(0);
+
+/// Duty for a level.
+pub const fn level_duty(level: u8) -> u32 {
+    let mut duty = PWM_MIN;
+    let mut i = 0;
+    while i < level {
+        duty = duty * 5 / 4;
+        i += 1;
+    }
+    if duty > DUTY_MAX {DUTY_MAX} else {duty}
+}
+
+const _: () = assert!(level_duty(LEVELS - 1) <= DUTY_MAX);
+const _: () = assert!(level_duty(LEVELS - 2) < level_duty(LEVELS - 1));
+
+/// The fixed duty while calibrating.
+pub fn duty() -> Option<u32> {
+    let level = LEVEL.read();
+    if level == 0 {None} else {Some(level_duty(level - 1))}
+}
+
+/// Show the test pattern while calibrating, called from the main loop.
+pub fn run() {
+    while LEVEL.read() != 0 {
+        hold_display(PATTERN, FIFTH);
+    }
+}
+
+/// Console: `cal [+|-|ok|off|vdd <mV>]`.
+pub fn cmd_cal(args: &mut Args) {
+    let level = LEVEL.read();
+    let battery = &crate::battery::BATTERY;
+    match args.word() {
+        b"" => if level == 0 {LEVEL.write(LEVELS / 2 + 1)},
+        b"+" if level != 0 => LEVEL.write((level + 1).min(LEVELS)),
+        b"-" if level != 0 => LEVEL.write((level - 1).max(1)),
+        b"off" => LEVEL.write(0),
+        b"ok" if level != 0 => {
+            let vdd = battery.millivolts();
+            let config = unsafe {CONFIG.as_mut()};
+            let vf = config.vf_mv as u32;
+            if vdd <= vf {
+                stm_common::dbgln!("cal Vdd {vdd}mV too low");
+                return;
+            }
+            config.pwm_scale = level_duty(level - 1) * (vdd - vf);
+            LEVEL.write(0);
+            stm_common::dbgln!("cal scale {} {:?}", config.pwm_scale,
+                               crate::config::save());
+            return;
+        }
+        b"vdd" => {
+            let mv = args.number();
+            let Some(mv) = mv.filter(|mv| (1800 ..= 3600).contains(mv)) else {
+                stm_common::dbgln!("cal vdd 1800..=3600");
+                return;
+            };
+            let measured = battery.millivolts();
+            if measured == 0 {
+                return;
+            }
+            // Vdd is proportional to the calibration value.
+            let cal = crate::battery::vrefint_cal() * mv as u32 / measured;
+            unsafe {CONFIG.as_mut()}.vref_cal = cal as u16;
+            stm_common::dbgln!("cal vref {cal} {:?}", crate::config::save());
+            return;
+        }
+        _ => {
+            stm_common::dbgln!("cal [+|-|ok|off|vdd <mV>]");
+            return;
+        }
+    }
+    match duty() {
+        Some(duty) => stm_common::dbgln!(
+            "cal level {} duty {duty} Vdd {}mV",
+            LEVEL.read() - 1, battery.millivolts()),
+        None => stm_common::dbgln!("cal off"),
+    }
+}
+
+#[test]
+fn levels() {
+    assert_eq!(level_duty(0), PWM_MIN);
+    assert_eq!(level_duty(1), 100);
+    assert_eq!(level_duty(LEVELS / 2), 378);
+    assert!(level_duty(LEVELS - 1) > DUTY_MAX * 3 / 4);
+}
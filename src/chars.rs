@@ -50,7 +50,20 @@ pub const fn map_str<const N: usize>(s: &[u8; N]) -> [u8; N] {
     result
 }
 
+/// Runtime version of `map_str`, mapping in place.  Characters that are not
+/// in the font become '?'.
+pub fn map_bytes(s: &mut [u8]) {
+    for c in s {
+        *c = find_char(*c).unwrap_or(const {map_char(b'?')});
+    }
+}
+
 pub const fn map_char(c: u8) -> u8 {
+    let Some(i) = find_char(c) else {panic!()};
+    i
+}
+
+pub const fn find_char(c: u8) -> Option<u8> {
     let mut low = 0;
     let mut high = CHARS.len();
     while high - low > 1 {
@@ -62,8 +75,7 @@ pub const fn map_char(c: u8) -> u8 {
             low = mid;
         }
     }
-    assert!(CHARS[low] == c);
-    low as u8
+    if CHARS[low] == c {Some(low as u8)} else {None}
 }
 
 pub const fn picture(c: char) -> u64 {
@@ -71,9 +83,9 @@ pub const fn picture(c: char) -> u64 {
     COLUMNS[map_char(c as u8) as usize]
 }
 
-pub const CHARS: &[u8] = b" 0123456789?ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+pub const CHARS: &[u8] = b" -0123456789:?ABCDEFGHIJKLMNOPQRSTUVWXYZ";
 pub const PICTURES: &'static [Picture] = &[
-    SPC, D0, D1, D2, D3, D4, D5, D6, D7, D8, D9, QUESTION,
+    SPC, DASH, D0, D1, D2, D3, D4, D5, D6, D7, D8, D9, COLON, QUESTION,
     A, B, C, D, E, F, G, H, I, J, K, L, M,
     N, O, P, Q, R, S, T, U, V, W, X, Y, Z,
 ];
@@ -87,6 +99,15 @@ const SPC: Picture = [
     "      ",
 ];
 
+const DASH: Picture = [
+    "      ",
+    "      ",
+    " ***  ",
+    "      ",
+    "      ",
+    "      ",
+];
+
 const D0: Picture = [
     " ***  ",
     "*   * ",
@@ -177,6 +198,15 @@ const D9: Picture = [
     "  *   ",
 ];
 
+const COLON: Picture = [
+    "      ",
+    "  *   ",
+    "      ",
+    "      ",
+    "  *   ",
+    "      ",
+];
+
 const QUESTION: Picture = [
     " ***  ",
     "*   * ",
@@ -527,8 +557,23 @@ pub const CORNERS: u64 = parse(&[
     "**  **",
 ]);
 pub const FOUR_IDOTS: u64 = parse(&IDOTS2);
+pub const BATTERY: u64 = parse(&[
+    "  **  ",
+    " *  * ",
+    " *  * ",
+    " *  * ",
+    " *  * ",
+    " **** ",
+]);
 
 
+#[test]
+fn runtime_map() {
+    let mut s = *b"12:34 Z!";
+    map_bytes(&mut s);
+    assert_eq!(s, map_str(b"12:34 Z?"));
+}
+
 #[test]
 fn chars_in_order() {
     // We do binary searches so make sure we get it right...
@@ -0,0 +1,148 @@
+//! Shows using the time of day: the time, a countdown to Christmas and a
+//! binary clock.
+
+use crate::marque::marque_string;
+use crate::pendsv::{FIFTH, SECOND, hold_display};
+use crate::rtc::DateTime;
+
+/// Scroll the time as HH:MM.
+pub fn show_time() {
+    let now = crate::rtc::read();
+    let mut buf = *b"00:00 ";
+    two_digits(&mut buf[0 .. 2], now.hour);
+    two_digits(&mut buf[3 .. 5], now.minute);
+    crate::chars::map_bytes(&mut buf);
+    marque_string(&mut 0, &buf, FIFTH);
+}
+
+/// Scroll "N DAYS TO XMAS", or the main message on the day.
+pub fn show_countdown() {
+    let days = days_to_xmas(&crate::rtc::read());
+    if days == 0 {
+        crate::season::SEASONS[crate::season::CHRISTMAS].show_main();
+        return;
+    }
+    let mut buf = [0; 20];
+    let mut len = decimal(&mut buf, days);
+    let text: &[u8] = if days == 1 {b" DAY TO XMAS "} else {b" DAYS TO XMAS "};
+    buf[len .. len + text.len()].copy_from_slice(text);
+    len += text.len();
+    crate::chars::map_bytes(&mut buf[.. len]);
+    marque_string(&mut 0, &buf[.. len], FIFTH);
+}
+
+/// Show a binary clock for `seconds`.  Columns are the BCD digits of
+/// HH MM SS, least significant bit at the bottom.
+pub fn binary_clock(seconds: u32) {
+    for _ in 0 .. seconds * SECOND / FIFTH {
+        hold_display(binary(&crate::rtc::read()), FIFTH);
+    }
+}
+
+/// Binary clock for ten seconds.
+pub fn show_binary() {
+    binary_clock(10);
+}
+
+/// Days until the next Christmas day, zero on the day.
+pub const fn days_to_xmas(dt: &DateTime) -> u32 {
+    let today = dt.days();
+    let xmas = crate::rtc::days(dt.year, 12, 25);
+    if today <= xmas {
+        (xmas - today) as u32
+    }
+    else {
+        (crate::rtc::days(dt.year + 1, 12, 25) - today) as u32
+    }
+}
+
+pub const fn binary(dt: &DateTime) -> u64 {
+    let digits = [dt.hour / 10, dt.hour % 10, dt.minute / 10, dt.minute % 10,
+                  dt.second / 10, dt.second % 10];
+    let mut display = 0;
+    let mut column = 0;
+    while column < 6 {
+        let mut bit = 0;
+        while bit < 4 {
+            if digits[column] & 1 << bit != 0 {
+                display |= 1 << column * 8 + 5 - bit;
+            }
+            bit += 1;
+        }
+        column += 1;
+    }
+    display
+}
+
+fn two_digits(buf: &mut [u8], n: u8) {
+    buf[0] = b'0' + n / 10;
+    buf[1] = b'0' + n % 10;
+}
+
+/// Format `n` in decimal at the start of `buf`, returning the length.
+pub fn decimal(buf: &mut [u8], n: u32) -> usize {
+    let mut len = 1;
+    while len < 10 && n >= 10u32.pow(len as u32) {
+        len += 1;
+    }
+    let mut n = n;
+    for c in buf[.. len].iter_mut().rev() {
+        *c = b'0' + (n % 10) as u8;
+        n /= 10;
+    }
+    len
+}
+
+#[cfg(test)]
+const fn date(year: u16, month: u8, day: u8) -> DateTime {
+    DateTime {year, month, day, hour: 0, minute: 0, second: 0}
+}
+
+#[test]
+fn xmas_countdown() {
+    assert_eq!(days_to_xmas(&date(2026, 10, 19)), 67);
+    assert_eq!(days_to_xmas(&date(2026, 12, 24)), 1);
+    assert_eq!(days_to_xmas(&date(2026, 12, 25)), 0);
+    assert_eq!(days_to_xmas(&date(2026, 12, 26)), 364);
+    // Leap year.
+    assert_eq!(days_to_xmas(&date(2027, 12, 31)), 360);
+    assert_eq!(days_to_xmas(&date(2028, 1, 1)), 359);
+    // Count down through a few years, one day at a time.
+    let mut last = days_to_xmas(&date(2024, 12, 31));
+    for year in 2025 .. 2030 {
+        for month in 1 ..= 12 {
+            for day in 1 ..= crate::rtc::days_in_month(year, month) {
+                let days = days_to_xmas(&date(year, month, day));
+                assert_eq!(days == 0, month == 12 && day == 25);
+                if last == 0 {
+                    assert!(days == 364 || days == 365, "{year}-{month}-{day}");
+                }
+                else {
+                    assert_eq!(days, last - 1);
+                }
+                last = days;
+            }
+        }
+    }
+}
+
+#[test]
+fn binary_digits() {
+    let dt = DateTime {
+        year: 2026, month: 1, day: 1, hour: 23, minute: 59, second: 8};
+    assert_eq!(binary(&dt), 0x04_00_24_28_30_10);
+}
+
+#[test]
+fn decimals() {
+    let dec = |n| {
+        let mut buf = [0; 10];
+        let len = decimal(&mut buf, n);
+        String::from_utf8(buf[.. len].to_vec()).unwrap()
+    };
+    assert_eq!(dec(0), "0");
+    assert_eq!(dec(9), "9");
+    assert_eq!(dec(10), "10");
+    assert_eq!(dec(364), "364");
+    assert_eq!(dec(u32::MAX), "4294967295");
+}
@@ -1,46 +1,75 @@
 use stm_common::vcell::UCell;
 
+use crate::console::Args;
+use crate::model::Led;
 use crate::pulse::PWM_DIV;
+use crate::record::{CONFIG_LEN, CONFIG_VERSION, KIND_CONFIG, LedCorr};
 
-const PWM_MIN: u32 = 80;
-const PWM_MAX: u32 = PWM_DIV / 2;
+/// The board config, defined in `record` so that host tools can share it.
+pub use crate::record::Config;
+
+/// Duty limits.  The CC1 interrupt needs to be well clear of the update
+/// interrupt at both ends: CC1 triggers PendSV, which builds the next frame
+/// and does the duty maths, and that has to finish before the update at the
+/// slow clock.  So the duty is capped mid-cycle.
+pub const PWM_MIN: u32 = 80;
+pub const DUTY_MAX: u32 = PWM_DIV / 2;
+
+/// LED parameters.  Vf is the datasheet typical at ≈1mA, and the target
+/// currents match the brightness of the original hand tuned configs at 3.0V.
+pub const WHITE: Led = Led {vf_mv: 2750, ohms: 330, target_ua: 200};
+pub const BLUE : Led = Led {vf_mv: 2750, ohms: 330, target_ua: 200};
+pub const RED  : Led = Led {vf_mv: 1900, ohms: 330, target_ua: 460};
+
+/// Default schedule, on at 5pm for 6 hours.
+const ON_START: u16 = 17 * 60;
+const ON_MINUTES: u16 = 6 * 60;
+
+/// Default battery thresholds.
+const LOW_MV: u16 = 2400;
+const CRITICAL_MV: u16 = 2100;
 
 const CPU_ID: *const [UCell<u32>; 3] = 0x1fff7590 as _;
 
-pub static CONFIG: UCell<Config> = UCell::default();
+/// All zeros, so that it is in the BSS, as there is no `.data` copy, until
+/// `generate_config` sets it.
+pub static CONFIG: UCell<Config> =
+    UCell::new(Config {led_corr: LedCorr([0; _]), ..Config::default()});
 
 pub static CONFIGS: [([u32; 3], Config); 4] = [
     // Led test board.
-    ([0x004c0072, 0x3245500b, 0x2031374c], Config::new(PWM_MAX)),
+    ([0x004c0072, 0x3245500b, 0x2031374c], Config::new(&WHITE)),
     // Slated for red.
-    ([0, 0, 0], Config::new(PWM_MAX / 2)),
+    ([0, 0, 0], Config::new(&RED)),
     // Orig white board.
-    ([0x004c007b, 0x3245500b, 0x2031374c], Config::new(PWM_MAX)),
+    ([0x004c007b, 0x3245500b, 0x2031374c], Config::new(&WHITE)),
     // Orig blue board.
-    ([0x004c0058, 0x3245500b, 0x2031374c], Config::new(PWM_MAX)),
+    ([0x004c0058, 0x3245500b, 0x2031374c], Config::new(&BLUE)),
 ];
 
-pub static GENERIC: Config = Config::new(PWM_MAX);
-
-#[derive(Clone, Copy)]
-#[derive_const(Default)]
-pub struct Config {
-    pub adc_over: u16,
-    pub adc_max: u16,
-    pub pwm_scale: u32,
-}
+pub static GENERIC: Config = Config::new(&WHITE);
 
 pub fn get() -> &'static Config {CONFIG.as_ref()}
 
 pub fn generate_config() {
+    let config = unsafe {CONFIG.as_mut()};
     let cpu_id = get_cpu_id();
-    for (cpu, config) in &CONFIGS {
-        if cpu_id[0] == cpu[0] && cpu_id[1] == cpu[1] && cpu_id[2] == cpu[2] {
-            *unsafe {CONFIG.as_mut()} = *config;
-            return;
-        } 
+    *config = match CONFIGS.iter().find(|(cpu, _)| *cpu == cpu_id) {
+        Some((_, c)) => *c,
+        None => GENERIC,
+    };
+
+    // Override from flash, if valid.
+    use crate::record::Store;
+    let store = crate::flash::store();
+    let page = crate::record::active(store);
+    if let Some(record) = crate::record::latest(store.page(page), KIND_CONFIG) {
+        let mut stored = *config;
+        stored.decode(record.payload);
+        if stored.is_valid() {
+            *config = stored;
+        }
     }
-    *unsafe {CONFIG.as_mut()} = GENERIC;
 }
 
 fn get_cpu_id() -> [u32; 3] {
@@ -49,28 +78,67 @@ fn get_cpu_id() -> [u32; 3] {
 }
 
 impl Config {
-    const fn new(pwm_max: u32) -> Config {
-        let max       = crate::adc::OVER3 + crate::adc::UNDER3;
-        let adc_over  = crate::adc::OVER3;
-        let adc_max   = crate::adc::OVER3 + crate::adc::UNDER3;
-        let range     = pwm_max - PWM_MIN;
-        let pwm_scale = (range * 65536).div_ceil(max);
-
-        let Ok(adc_over) = adc_over.try_into() else {panic!()};
-        let Ok(adc_max)  = adc_max .try_into() else {panic!()};
-
-        let c = Config {adc_over, adc_max, pwm_scale};
-        c.check(pwm_max);
+    pub const fn new(led: &Led) -> Config {
+        let Ok(vf_mv) = led.vf_mv.try_into() else {panic!()};
+        let Ok(led_ohms) = led.ohms.try_into() else {panic!()};
+        let c = Config {
+            vf_mv, pwm_scale: led.pwm_scale(), led_ohms, source_ohms: 0,
+            current_cap: 0, lsi_trim: 0,
+            on_start: ON_START, on_minutes: ON_MINUTES, season: 0,
+            low_mv: LOW_MV, critical_mv: CRITICAL_MV, temp_comp: 0,
+            vref_cal: 0, led_corr: LedCorr([255; 36])};
+        c.check();
         c
     }
 
-    pub const fn calc_duty(&self, delta: u32) -> u32 {
-        (self.pwm_scale * delta >> 16) + PWM_MIN
+    /// Duty for constant brightness at the given Vdd.  This takes a division,
+    /// but we only do it per ADC reading.
+    pub const fn calc_duty(&self, vdd_mv: u32) -> u32 {
+        let vf_mv = self.vf_mv as u32;
+        if vdd_mv <= vf_mv {
+            return DUTY_MAX;
+        }
+        let duty = self.pwm_scale / (vdd_mv - vf_mv);
+        if duty < PWM_MIN {PWM_MIN}
+        else if duty > DUTY_MAX {DUTY_MAX}
+        else {duty}
     }
 
-    const fn check(&self, pwm_max: u32) {
-        assert!(self.calc_duty(self.adc_max as u32) == pwm_max);
-        assert!(self.calc_duty(0) == PWM_MIN);
-        assert!(self.calc_duty(0) >= 78);
+    const fn check(&self) {
+        assert!(self.calc_duty(0) == DUTY_MAX);
+        // At the highest Vdd, the model doesn't need the floor.
+        assert!(self.pwm_scale / (5000 - self.vf_mv as u32) >= PWM_MIN);
+        assert!(PWM_MIN >= 78);
+        assert!(self.is_valid());
     }
 }
+
+/// Console: `save [erase]`.  Write the current config to flash, or erase the
+/// stored config, reverting to the compiled one on the next boot.
+pub fn cmd_save(args: &mut Args) {
+    use crate::record::Store;
+    let erase = match args.word() {
+        b"" => false,
+        b"erase" => true,
+        _ => {
+            stm_common::dbgln!("save [erase]");
+            return;
+        }
+    };
+    let result = if erase {
+        crate::flash::unlocked(|flash| (0 .. crate::record::PAGES)
+                               .try_for_each(|page| flash.erase(page)))
+    }
+    else {
+        save()
+    };
+    stm_common::dbgln!("save {:?}", result);
+}
+
+/// Write the current config to flash.
+pub fn save() -> Result<(), crate::record::Error> {
+    let mut buf = [0; CONFIG_LEN];
+    let len = get().encode(&mut buf);
+    crate::flash::unlocked(|flash| crate::record::append(
+        flash, KIND_CONFIG, CONFIG_VERSION, &buf[.. len]))
+}
@@ -0,0 +1,147 @@
+//! Serial console on the debug UART.  The debug ISR collects received
+//! characters into a line, and `poll()` runs complete lines from application
+//! context.
+
+use stm_common::vcell::{UCell, VCell};
+
+pub const LINE_LEN: usize = 40;
+
+/// Console commands, matched on the first word of the line.
+static COMMANDS: &[(&str, fn(&mut Args))] = &[
+    ("bat", crate::battery::cmd_bat),
+    ("boot", crate::boot::cmd_boot),
+    ("cal", crate::calibrate::cmd_cal),
+    ("corr", crate::leds::cmd_corr),
+    ("crash", crate::crash::cmd_crash),
+    ("date", crate::rtc::cmd_date),
+    ("load", crate::model::cmd_load),
+    ("save", crate::config::cmd_save),
+    ("msg", crate::messages::cmd_msg),
+    ("sched", crate::schedule::cmd_sched),
+    ("season", crate::season::cmd_season),
+    ("stack", crate::stack::cmd_stack),
+    ("tcomp", crate::temp::cmd_tcomp),
+    ("temp", crate::temp::cmd_temp),
+    ("trace", crate::trace::cmd_trace),
+    ("trim", crate::rtc::cmd_trim),
+];
+
+/// The line being received.
+static LINE: UCell<[u8; LINE_LEN]> = UCell::new([0; _]);
+/// Number of characters in LINE.
+static LEN: VCell<u8> = VCell::new(0);
+/// Set by the ISR once LINE is complete, cleared once the command is run.
+static READY: VCell<bool> = VCell::new(false);
+
+/// Argument parsing for console commands.
+pub struct Args<'a>(&'a [u8]);
+
+/// Receive a character, called from the debug ISR.
+pub fn rx(c: u8) {
+    if READY.read() {
+        return;                         // Busy, drop it.
+    }
+    let len = LEN.read() as usize;
+    match c {
+        b'\r' | b'\n' => if len != 0 {READY.write(true)},
+        8 | 127 => LEN.write(len.saturating_sub(1) as u8),
+        _ => if len < LINE_LEN {
+            unsafe {LINE.as_mut()[len] = c};
+            LEN.write(len as u8 + 1);
+        },
+    }
+}
+
+/// Run a received command, if any.
+pub fn poll() {
+    if !crate::DEBUG_ENABLE || !READY.read() {
+        return;
+    }
+    let mut args = Args(&LINE.as_ref()[.. LEN.read() as usize]);
+    let word = args.word();
+    match COMMANDS.iter().find(|(name, _)| name.as_bytes() == word) {
+        Some((_, command)) => crate::cpu::fast(|| command(&mut args)),
+        None => stm_common::dbgln!("?"),
+    }
+    LEN.write(0);
+    READY.write(false);
+}
+
+impl<'a> Args<'a> {
+    fn skip_spaces(&mut self) {
+        while let [b' ', rest @ ..] = self.0 {
+            self.0 = rest;
+        }
+    }
+
+    /// Are there no more arguments?
+    pub fn done(&mut self) -> bool {
+        self.skip_spaces();
+        self.0.is_empty()
+    }
+
+    /// The next space separated word.
+    pub fn word(&mut self) -> &'a [u8] {
+        self.skip_spaces();
+        let len =
+            self.0.iter().position(|&c| c == b' ').unwrap_or(self.0.len());
+        let (word, rest) = self.0.split_at(len);
+        self.0 = rest;
+        word
+    }
+
+    /// The rest of the line, less leading spaces.
+    pub fn rest(&mut self) -> &'a [u8] {
+        self.skip_spaces();
+        core::mem::take(&mut self.0)
+    }
+
+    /// The next decimal number, optionally negative.  A single `-`, `:` or
+    /// `/` following the digits is consumed, so that dates and times parse as
+    /// a sequence of numbers.
+    pub fn number(&mut self) -> Option<i32> {
+        self.skip_spaces();
+        let (negative, digits) = match self.0 {
+            [b'-', rest @ ..] => (true, rest),
+            _ => (false, self.0),
+        };
+        let len = digits.iter().position(|c| !c.is_ascii_digit())
+            .unwrap_or(digits.len());
+        if len == 0 || len > 9 {
+            return None;
+        }
+        let mut n = 0i32;
+        for &c in &digits[.. len] {
+            n = n * 10 + (c - b'0') as i32;
+        }
+        self.0 = match &digits[len ..] {
+            [b'-' | b':' | b'/', rest @ ..] => rest,
+            rest => rest,
+        };
+        Some(if negative {-n} else {n})
+    }
+}
+
+#[test]
+fn parse_args() {
+    let mut args = Args(b"  date 2026-10-19 12:34:56 ");
+    assert_eq!(args.word(), b"date");
+    let numbers: [_; 6] = core::array::from_fn(|_| args.number());
+    assert_eq!(numbers, [2026, 10, 19, 12, 34, 56].map(Some));
+    assert!(args.done());
+    assert_eq!(args.number(), None);
+
+    let mut args = Args(b"trim -150 x");
+    assert_eq!(args.word(), b"trim");
+    assert_eq!(args.number(), Some(-150));
+    assert!(!args.done());
+    assert_eq!(args.number(), None);
+    assert_eq!(args.word(), b"x");
+    assert!(args.done());
+
+    let mut args = Args(b"msg add  HELLO WORLD");
+    assert_eq!(args.word(), b"msg");
+    assert_eq!(args.word(), b"add");
+    assert_eq!(args.rest(), b"HELLO WORLD");
+    assert!(args.done());
+}
@@ -1,4 +1,5 @@
-use stm_common::{interrupt::VectorTable, utils::barrier};
+use stm_common::{interrupt, interrupt::VectorTable, utils::barrier};
+use stm_common::vcell::VCell;
 
 use crate::CONFIG;
 
@@ -11,25 +12,113 @@ pub const PRIO_DEBUG: u8 = 0x40;
 /// Priority for the PWM ISR.
 pub const PRIO_PULSE: u8 = 0;
 
-pub fn init() {
+/// The TIM3 clock, the same at every system clock, so that the PWM cycle is
+/// the same number of counts.
+pub const PWM_CLK: u32 = 250_000;
+
+/// A system clock, and the peripheral settings that depend on it.  The
+/// allowed clocks are consts, so `new` checks them at compile time.
+#[derive(Clone, Copy)]
+pub struct Clock {
+    pub clk: u32,
+    /// RCC CR HSIDIV, as a power of two.
+    hsidiv: u8,
+    /// TIM3 prescaler, for `PWM_CLK`.
+    pub pwm_prescale: u32,
+    /// USART1 BRR, see `debug::brr`.
+    pub brr: u32,
+    /// ADC sampling time code, see `adc::smp`.
+    pub smp: u8,
+    /// Loops to wait for the ADC regulator in `adc::start`.
+    pub adc_delay: u32,
+}
+
+impl Clock {
+    pub const fn new(clk: u32) -> Clock {
+        assert!(clk <= 16_000_000 && 16_000_000 % clk == 0);
+        let div = 16_000_000 / clk;
+        assert!(div.is_power_of_two());
+        assert!(div.ilog2() < 8);
+        assert!(clk % PWM_CLK == 0);
+        let pwm_prescale = clk / PWM_CLK;
+        assert!(pwm_prescale <= 65536);
+        Clock {
+            clk, hsidiv: div.ilog2() as u8, pwm_prescale,
+            brr: crate::debug::brr(clk), smp: crate::adc::smp(clk),
+            adc_delay: if clk > 250_000 {clk / 250_000} else {0},
+        }
+    }
+    /// LP run, voltage range 2, is allowed up to 2MHz.
+    const fn lp_run(&self) -> bool {self.clk <= 2_000_000}
+}
+
+/// The normal clock.
+pub const SLOW: Clock = Clock::new(CONFIG.clk);
+/// The clock for `fast`, the same as `SLOW` if there is no `fast_clock`.
+pub const FAST: Clock =
+    if CONFIG.fast_clk == 0 {SLOW} else {Clock::new(CONFIG.fast_clk)};
+
+static IS_FAST: VCell<bool> = VCell::new(false);
+
+/// The current clock.
+pub fn clock() -> &'static Clock {
+    if IS_FAST.read() {&FAST} else {&SLOW}
+}
+
+/// Set-up the system clock.  Also used after wake-up from STOP.
+pub fn clocks() {
     let pwr = unsafe {&*stm32g030::PWR::ptr()};
     let rcc = unsafe {&*stm32g030::RCC::ptr()};
+    let clock = clock();
+
+    // Leave LP run, and go to voltage range 1, before going over 2MHz.
+    // Range 1 needs no flash wait states up to 24MHz.
+    if !clock.lp_run() && pwr.CR1.read().VOS().bits() != 1 {
+        pwr.CR1.modify(|_, w| w.LPR().clear_bit());
+        while pwr.SR2.read().REGLPF().bit() {}
+        pwr.CR1.modify(|_, w| w.VOS().bits(1));
+        while pwr.SR2.read().VOSF().bit() {}
+    }
 
     // Set the HSI16 divider...
-    if CONFIG.clk < 16_000_000 {
-        const DIV: u32 = 16_000_000 / CONFIG.clk;
-        const {assert!(16_000_000 % CONFIG.clk == 0)};
-        const {assert!(DIV.is_power_of_two())};
-        const HSIDIV: u32 = DIV.ilog2();
-        const {assert!(HSIDIV < 8)};
-        rcc.CR.modify(|_, w| w.HSIDIV().bits(HSIDIV as u8));
-    }
+    rcc.CR.modify(|_, w| w.HSIDIV().bits(clock.hsidiv));
 
-    if CONFIG.clk <= 2_000_000 {
+    if clock.lp_run() {
         // Enter LP run mode, voltage range 2.
         pwr.CR1.modify(|_, w| w.LPR().set_bit().VOS().bits(2));
     }
+}
 
+/// Switch between the `SLOW` and `FAST` clocks, and reconfigure the
+/// peripherals to match.  TIM3 takes the new prescaler at the next update,
+/// so the PWM cycle in progress is stretched or shortened once.  An ADC
+/// conversion in progress may get a short sample time once.
+pub fn set_clock(fast: bool) {
+    if CONFIG.fast_clk == 0 || fast == IS_FAST.read() {
+        return;
+    }
+    // Finish sending at the old baud rate.
+    if crate::DEBUG_ENABLE {
+        stm_common::debug::flush::<crate::debug::DebugMeta>();
+    }
+    interrupt::disable_all();
+    IS_FAST.write(fast);
+    clocks();
+    crate::pulse::set_prescale(clock().pwm_prescale);
+    crate::debug::set_brr(clock().brr);
+    interrupt::enable_all();
+}
+
+/// Run `f` at the `FAST` clock, e.g., for the console or heavy effects.
+pub fn fast<T>(f: impl FnOnce() -> T) -> T {
+    let was = IS_FAST.read();
+    set_clock(true);
+    let result = f();
+    set_clock(was);
+    result
+}
+
+pub fn init() {
     // Clear the BSS.
     if !cfg!(test) {
         barrier();
@@ -43,16 +132,36 @@ pub fn init() {
             }
         }
         barrier();
+
+        // For the high-water mark.
+        crate::stack::paint();
     }
 
+    // After the BSS clear, as the current clock is kept there.
+    clocks();
+    crate::power::init();
+
     crate::config::generate_config();
+    crate::boot::init();
+    crate::random::init();
 }
 
 #[derive(Clone, Copy)]
 pub struct Config {
     pub clk: u32,
+    /// Clock for `fast`, or zero for none.
+    pub fast_clk: u32,
     /// Turn off debug...
     pub no_debug: bool,
+    /// Show crashes on the LEDs at boot, see `crash::show`.
+    pub crash_blink: bool,
+    /// Run the independent watchdog, see `watchdog`.
+    pub watchdog: bool,
+    /// Trace the PWM tick timing, see `trace`.
+    pub trace: bool,
+    /// Low power options, see `power`.
+    pub flash_powerdown: bool,
+    pub sleep_clocks: bool,
     pub vectors: VectorTable,
 }
 
@@ -64,21 +173,29 @@ unsafe extern "C" {
     static mut __bss_start: u8;
     static mut __bss_end: u8;
     #[cfg(target_os = "none")]
-    static end_of_ram: u8;
+    pub static end_of_ram: u8;
 }
 
 #[cfg(not(target_os = "none"))]
 #[allow(non_upper_case_globals)]
-static end_of_ram: u8 = 0;
+pub static end_of_ram: u8 = 0;
 
 impl Config {
     pub const fn new(clk: u32) -> Config {
         Config {
-            clk, no_debug: false,
+            clk, fast_clk: 0, no_debug: false, crash_blink: false,
+            watchdog: false, trace: false, flash_powerdown: false,
+            sleep_clocks: false,
             vectors: VectorTable::new(
-                    &raw const end_of_ram, crate::main, bugger),
+                    &raw const end_of_ram, crate::main,
+                    crate::fault::fault_entry),
         }
     }
+    /// Allow switching to a faster clock at runtime, see `fast`.
+    pub const fn fast_clock(&mut self, clk: u32) -> &mut Self {
+        self.fast_clk = clk;
+        self
+    }
     pub const fn isr(&mut self,
                      isr: stm32g030::Interrupt, handler: fn()) -> &mut Self {
         self.vectors.isr[isr as usize] = handler;
@@ -86,19 +203,3 @@ impl Config {
         self
     }
 }
-
-unsafe extern "C" {
-    #[link_name = "llvm.frameaddress"]
-    fn frameaddress(level: i32) -> *const u8;
-}
-
-fn bugger() {
-    let fp = unsafe {frameaddress(0)};
-    // The exception PC is at +0x18, but then LLVM pushes an additional 8
-    // bytes to form the frame.
-    let pcp = fp.wrapping_add(0x20);
-    let pc = unsafe {*(pcp as *const u32)};
-    stm_common::dbgln!("Crash @ {pc:#010x}");
-    stm_common::debug::flush::<crate::debug::DebugMeta>();
-    stm_common::utils::reboot();
-}
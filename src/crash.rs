@@ -0,0 +1,157 @@
+//! Crash records that survive the reboot, in `.noinit` RAM.  The record is
+//! reported on the next boot, over the console, and if configured with
+//! `cpu::Config::crash_blink`, as an error code on the LEDs.
+
+use stm_common::vcell::UCell;
+
+use crate::console::Args;
+use crate::marque::marque_string;
+use crate::pendsv::FIFTH;
+
+const MAGIC: u32 = 0xc7a5_4ed0;
+
+#[derive(Clone, Copy)]
+pub struct Crash {
+    magic: u32,
+    /// Faulting PC, zero for a panic.
+    pub pc: u32,
+    pub lr: u32,
+    pub xpsr: u32,
+    pub sp: u32,
+    /// Hash of the panic location, zero for a fault.
+    pub panic: u32,
+    /// Number of crashes since power on.
+    pub count: u32,
+    /// Set when a crash is recorded, cleared once reported.
+    pub pending: bool,
+    check: u32,
+}
+
+#[unsafe(link_section = ".noinit")]
+static CRASH: UCell<Crash> = UCell::new(Crash {
+    magic: 0, pc: 0, lr: 0, xpsr: 0, sp: 0, panic: 0, count: 0,
+    pending: false, check: 0});
+
+impl Crash {
+    const fn checksum(&self) -> u32 {
+        !(self.magic ^ self.pc ^ self.lr.rotate_left(1)
+          ^ self.xpsr.rotate_left(2) ^ self.sp.rotate_left(3)
+          ^ self.panic.rotate_left(4) ^ self.count.rotate_left(5)
+          ^ self.pending as u32)
+    }
+
+    const fn is_valid(&self) -> bool {
+        self.magic == MAGIC && self.check == self.checksum()
+    }
+}
+
+/// The last crash, if any since power on.
+pub fn last() -> Option<Crash> {
+    let crash = *CRASH.as_ref();
+    if crash.is_valid() {Some(crash)} else {None}
+}
+
+fn record(pc: u32, lr: u32, xpsr: u32, sp: u32, panic: u32) {
+    let count = last().map_or(0, |c| c.count);
+    let mut crash = Crash {
+        magic: MAGIC, pc, lr, xpsr, sp, panic,
+        count: count.wrapping_add(1), pending: true, check: 0};
+    crash.check = crash.checksum();
+    *unsafe {CRASH.as_mut()} = crash;
+}
+
+/// Record a fault, from the stacked registers.
+pub fn record_fault(pc: u32, lr: u32, xpsr: u32, sp: u32) {
+    record(pc, lr, xpsr, sp, 0);
+}
+
+/// Record a panic, by a hash of its location.
+pub fn record_panic(location: Option<&core::panic::Location>) {
+    let hash = location.map_or(1, |l| location_hash(l.file(), l.line()));
+    record(0, 0, 0, 0, hash);
+}
+
+/// FNV-1a hash of the file and line, never zero.
+pub const fn location_hash(file: &str, line: u32) -> u32 {
+    let mut hash = 0x811c9dc5u32;
+    let file = file.as_bytes();
+    let mut i = 0;
+    while i < file.len() {
+        hash = (hash ^ file[i] as u32).wrapping_mul(0x01000193);
+        i += 1;
+    }
+    hash = (hash ^ line).wrapping_mul(0x01000193);
+    if hash == 0 {1} else {hash}
+}
+
+fn print(crash: &Crash) {
+    stm_common::dbgln!(
+        "Crash #{} pc {:#010x} lr {:#010x} xpsr {:#010x} sp {:#010x} \
+         panic {:#010x}", crash.count, crash.pc, crash.lr, crash.xpsr,
+        crash.sp, crash.panic);
+}
+
+/// Report a pending crash on the console, called at boot.
+pub fn report() {
+    if let Some(crash) = last() && crash.pending {
+        print(&crash);
+    }
+}
+
+/// Show a pending crash on the LEDs, as "ERR" then P for a panic or F for a
+/// fault, and four hex digits of the hash or PC.  Then mark it reported.
+pub fn show() {
+    let Some(mut crash) = last() else {return};
+    if !crash.pending {
+        return;
+    }
+    if crate::CONFIG.crash_blink {
+        let (kind, code) = if crash.panic != 0 {(b'P', crash.panic)}
+            else {(b'F', crash.pc)};
+        let mut buf = *b"ERR X 0000  ";
+        buf[4] = kind;
+        hex(&mut buf[6 .. 10], code);
+        crate::chars::map_bytes(&mut buf);
+        for _ in 0 .. 3 {
+            marque_string(&mut 0, &buf, FIFTH);
+        }
+    }
+    crash.pending = false;
+    crash.check = crash.checksum();
+    *unsafe {CRASH.as_mut()} = crash;
+}
+
+/// Fill `buf` with the low hex digits of `n`.
+pub fn hex(buf: &mut [u8], n: u32) {
+    let mut n = n;
+    for c in buf.iter_mut().rev() {
+        *c = b"0123456789ABCDEF"[n as usize & 15];
+        n >>= 4;
+    }
+}
+
+/// Console: `crash`, the last crash since power on.
+pub fn cmd_crash(_: &mut Args) {
+    match last() {
+        Some(crash) => print(&crash),
+        None => stm_common::dbgln!("No crash"),
+    }
+}
+
+impl crate::cpu::Config {
+    /// Show crashes on the LEDs at boot.
+    pub const fn crash_blink(&mut self) -> &mut Self {
+        self.crash_blink = true;
+        self
+    }
+}
+
+#[test]
+fn hex_digits() {
+    let mut buf = [0; 4];
+    hex(&mut buf, 0x1234abcd);
+    assert_eq!(&buf, b"ABCD");
+    hex(&mut buf, 0xf);
+    assert_eq!(&buf, b"000F");
+    assert_ne!(location_hash("src/a.rs", 1), location_hash("src/a.rs", 2));
+}
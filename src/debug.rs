@@ -38,11 +38,63 @@ impl Meta for DebugMeta {
 static DEBUG: Debug<DebugMeta> = Default::default();
 
 fn debug_isr() {
+    let uart = DebugMeta.uart();
+    let isr = uart.ISR.read();
+    if isr.ORE().bit() {
+        uart.ICR.write(|w| w.ORECF().set_bit());
+    }
+    if isr.RXNE().bit() {
+        let c = uart.RDR.read().bits() as u8;
+        // The line is half-duplex, so ignore the echo of our own output.
+        if isr.TC().bit() {
+            crate::console::rx(c);
+        }
+    }
     DEBUG.isr();
 }
 
 const BAUD: u32 = 9600;
 
+/// USART ISR.TXFNF, the TX FIFO has room.
+const TXFNF: u32 = 1 << 7;
+
+/// Send raw bytes, for `log`.  The formatted output is drained first, then
+/// the bytes go straight into the TX FIFO, waiting if it is full.  Output from
+/// a higher priority in the meantime may get mixed in.
+pub fn write_raw(data: &[u8]) {
+    if !DEBUG_ENABLE || !DebugMeta.is_init() {
+        return;
+    }
+    debug::flush::<DebugMeta>();
+    let uart = DebugMeta.uart();
+    for &b in data {
+        while uart.ISR.read().bits() & TXFNF == 0 {}
+        uart.TDR.write(|w| w.bits(b as u32));
+    }
+}
+
+/// USART BRR for `BAUD` at a clock.
+pub const fn brr(clk: u32) -> u32 {
+    let brr = (clk + BAUD / 2) / BAUD;
+    assert!(brr > 10);
+    assert!(brr < 65536);
+    brr
+}
+
+/// Change the baud rate divider, after a clock change.
+pub fn set_brr(brr: u32) {
+    let rcc = unsafe {&*stm32g030::RCC::ptr()};
+    if !DEBUG_ENABLE || !rcc.APBENR2.read().USART1EN().bit() {
+        return;
+    }
+    let uart = DebugMeta.uart();
+    // Let the last character out.
+    while !uart.ISR.read().TC().bit() {}
+    uart.CR1.modify(|_, w| w.UE().clear_bit());
+    uart.BRR.write(|w| w.bits(brr));
+    uart.CR1.modify(|_, w| w.UE().set_bit());
+}
+
 pub fn init() {
     check_vtors();
     let gpioa = unsafe {&*stm32g030::GPIOA::ptr()};
@@ -54,19 +106,20 @@ pub fn init() {
     DEBUG.w.write(0);
     DEBUG.r.write(0);
 
-    // Configure UART lines.
+    // Configure UART lines.  The RX pins are all used for LEDs, so we run
+    // the UART half-duplex on the TX pin, open drain with a pull-up.
     gpioa.AFRH.modify(|_, w| w.AFSEL9().bits(1));
+    gpioa.OTYPER.modify(|_, w| w.OT9().set_bit());
+    gpioa.PUPDR.modify(|_, w| w.PUPD9().bits(1));
     gpioa.MODER.modify(|_, w| w.MODER9().bits(2));
 
-    // Set-up the UART TX.  TODO - we should enable RX at some point.  The dbg*
-    // macros will work after this.
+    // Set-up the UART.  The dbg* macros will work after this.
 
-    const BRR: u32 = (CONFIG.clk + BAUD / 2) / BAUD;
-    const {assert!(BRR > 10)};
-    const {assert!(BRR < 65536)};
-    uart.BRR.write(|w| w.bits(BRR)); // FIXME
+    uart.BRR.write(|w| w.bits(crate::cpu::clock().brr)); // FIXME
     // uart.PRESC.write(|w| w.bits(0));
-    uart.CR1.write(|w| w.FIFOEN().set_bit().TE().set_bit().UE().set_bit());
+    uart.CR3.write(|w| w.HDSEL().set_bit());
+    uart.CR1.write(|w| w.FIFOEN().set_bit().RXNEIE().set_bit()
+                   .TE().set_bit().RE().set_bit().UE().set_bit());
 
     interrupt::enable_priority(INTERRUPT, PRIO_DEBUG);
 
@@ -80,6 +133,7 @@ pub fn init() {
 #[cfg(target_os = "none")]
 #[panic_handler]
 fn ph(info: &core::panic::PanicInfo) -> ! {
+    crate::crash::record_panic(info.location());
     stm_common::dbgln!("{info}");
     debug::flush::<DebugMeta>();
     stm_common::utils::reboot();
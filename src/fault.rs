@@ -0,0 +1,211 @@
+//! Fault handling.  All the unused exception vectors, including HardFault,
+//! point at `fault_entry`, which finds the stacked registers and passes them
+//! to `fault`.
+//!
+//! The M0+ has no fault status registers, so the report is decoded from what
+//! we have: the stacked registers, the faulting instruction, and the active
+//! exception.  Then we do a bounded backtrace, by scanning the stack for
+//! words that look like return addresses: odd addresses in flash, following a
+//! BL or BLX.
+
+use crate::record::BASE as FLASH_END;
+
+const FLASH_START: u32 = 0x0800_0000;
+const RAM_START: u32 = 0x2000_0000;
+/// End of RAM, as in `device.x`.
+const RAM_END: u32 = 0x2000_2000;
+
+/// Number of stack words scanned for the backtrace.
+const SCAN_WORDS: u32 = 128;
+/// Maximum number of backtrace entries.
+const MAX_TRACE: usize = 8;
+
+/// Exception number of HardFault.
+const HARD_FAULT: u32 = 3;
+
+/// xPSR bits.
+const XPSR_T: u32 = 1 << 24;
+const XPSR_ALIGN: u32 = 1 << 9;
+
+/// Pass the stack pointer in use at the exception (from EXC_RETURN bit 2),
+/// EXC_RETURN and IPSR to `fault`.
+#[cfg(target_arch = "arm")]
+#[unsafe(naked)]
+pub fn fault_entry() {
+    core::arch::naked_asm!(
+        "movs r0, #4",
+        "mov r1, lr",
+        "tst r0, r1",
+        "bne 1f",
+        "mrs r0, msp",
+        "b 2f",
+        "1:",
+        "mrs r0, psp",
+        "2:",
+        "mrs r2, ipsr",
+        "bl {fault}",
+        fault = sym fault,
+    )
+}
+
+#[cfg(not(target_arch = "arm"))]
+pub fn fault_entry() {
+    fault(0, 0, 0);
+}
+
+/// Read a word, if it is in RAM or flash.
+fn read(address: u32) -> Option<u32> {
+    let ok = address & 3 == 0
+        && ((RAM_START .. RAM_END).contains(&address)
+            || (FLASH_START .. FLASH_END).contains(&address));
+    if ok {Some(unsafe {*(address as *const u32)})} else {None}
+}
+
+/// Read an instruction halfword, if it is in flash.
+fn read_code(address: u32) -> Option<u16> {
+    if address & 1 == 0 && (FLASH_START .. FLASH_END).contains(&address) {
+        Some(unsafe {*(address as *const u16)})
+    }
+    else {
+        None
+    }
+}
+
+extern "C" fn fault(frame: u32, exc_return: u32, ipsr: u32) -> ! {
+    // The stacked r0-r3, r12, lr, pc, xpsr.
+    let regs: [u32; 8] = core::array::from_fn(
+        |i| read(frame + 4 * i as u32).unwrap_or(0));
+    let [_, _, _, _, _, lr, pc, xpsr] = regs;
+    // The SP before the exception, allowing for the alignment padding.
+    let sp = frame + 32 + if xpsr & XPSR_ALIGN != 0 {4} else {0};
+    crate::crash::record_fault(pc, lr, xpsr, sp);
+
+    if crate::DEBUG_ENABLE {
+        report(&regs, sp, exc_return, ipsr);
+        stm_common::debug::flush::<crate::debug::DebugMeta>();
+    }
+    stm_common::utils::reboot();
+}
+
+fn report(regs: &[u32; 8], sp: u32, exc_return: u32, ipsr: u32) {
+    use stm_common::dbgln;
+    let [r0, r1, r2, r3, r12, lr, pc, xpsr] = *regs;
+    if ipsr == HARD_FAULT {
+        dbgln!("HardFault: {}", cause(pc, xpsr, read_code(pc & !1)));
+    }
+    else {
+        dbgln!("Unexpected exception {ipsr}");
+    }
+    dbgln!("r0  {r0:#010x} r1 {r1:#010x} r2 {r2:#010x} r3   {r3:#010x}");
+    dbgln!("r12 {r12:#010x} lr {lr:#010x} pc {pc:#010x} xpsr {xpsr:#010x}");
+    let mode = if exc_return & 8 == 0 {"handler"} else {"thread"};
+    let stack = if exc_return & 4 == 0 {"MSP"} else {"PSP"};
+    dbgln!("sp {sp:#010x} ({stack}) in {mode} mode, exception {}",
+           xpsr & 0x3f);
+
+    let mut trace = [0; MAX_TRACE];
+    let n = backtrace(sp, &mut trace, read, read_code);
+    for &address in &trace[.. n] {
+        dbgln!("  return to {address:#010x}");
+    }
+}
+
+/// A guess at the cause of a HardFault, from the stacked PC and xPSR, and the
+/// faulting instruction.
+pub const fn cause(pc: u32, xpsr: u32, insn: Option<u16>) -> &'static str {
+    if xpsr & XPSR_T == 0 {
+        return "T bit clear, bad function pointer?";
+    }
+    let Some(insn) = insn else {return "PC out of range"};
+    match insn >> 8 {
+        0xde => "UDF, abort or unreachable",
+        0xbe => "BKPT without a debugger",
+        0xdf => "SVC",
+        _ if is_load_store(insn) => "load/store, bad or unaligned address",
+        _ if pc & 1 != 0 => "odd PC",
+        _ => "unknown",
+    }
+}
+
+/// Is a 16-bit Thumb instruction a load or store?
+const fn is_load_store(insn: u16) -> bool {
+    matches!(insn >> 11,
+             0b01001                    // LDR literal.
+             | 0b01010 | 0b01011        // Register offset.
+             | 0b01100 ..= 0b10011      // Immediate offset, SP relative.
+             | 0b11000 | 0b11001)       // LDM, STM.
+        || insn & 0xf600 == 0xb400      // PUSH, POP.
+}
+
+/// Does the instruction before the return address `ret` look like a call?
+pub fn is_call_return(ret: u32, read_code: impl Fn(u32) -> Option<u16>)
+    -> bool
+{
+    if ret & 1 == 0 {
+        return false;
+    }
+    let ret = ret & !1;
+    // BL is two halfwords: 11110xxx xxxxxxxx, 11x1xxxx xxxxxxxx.
+    if let (Some(hi), Some(lo)) = (read_code(ret.wrapping_sub(4)),
+                                   read_code(ret.wrapping_sub(2)))
+        && hi & 0xf800 == 0xf000 && lo & 0xd000 == 0xd000 {
+        return true;
+    }
+    // BLX register: 01000111 1xxxx000.
+    read_code(ret.wrapping_sub(2)).is_some_and(|i| i & 0xff87 == 0x4780)
+}
+
+/// Scan the stack from `sp` for return addresses, with the Thumb bit
+/// cleared.
+pub fn backtrace(sp: u32, trace: &mut [u32],
+                 read: impl Fn(u32) -> Option<u32>,
+                 read_code: impl Fn(u32) -> Option<u16> + Copy) -> usize {
+    let mut n = 0;
+    for i in 0 .. SCAN_WORDS {
+        if n >= trace.len() {
+            break;
+        }
+        let Some(word) = read(sp + 4 * i) else {break};
+        if is_call_return(word, read_code) {
+            trace[n] = word & !1;
+            n += 1;
+        }
+    }
+    n
+}
+
+#[test]
+fn causes() {
+    assert_eq!(cause(0x0800_0100, 0, Some(0)),
+               "T bit clear, bad function pointer?");
+    assert_eq!(cause(0x0800_0100, XPSR_T, None), "PC out of range");
+    assert_eq!(cause(0x0800_0100, XPSR_T, Some(0xdefe)),
+               "UDF, abort or unreachable");
+    // ldr r0, [r1, #4]; str r0, [r1]; pop {r4, pc}; ldrh r0, [r1]
+    for insn in [0x6848, 0x6008, 0xbd10, 0x8808] {
+        assert_eq!(cause(0x0800_0100, XPSR_T, Some(insn)),
+                   "load/store, bad or unaligned address", "{insn:#x}");
+    }
+    // adds r0, #1
+    assert_eq!(cause(0x0800_0100, XPSR_T, Some(0x3001)), "unknown");
+}
+
+#[test]
+fn scan() {
+    // Code: a BL at 0x08000100, a BLX r3 at 0x08000202, and adds at
+    // 0x08000300.
+    let code = |a: u32| match a {
+        0x0800_0100 => Some(0xf000),
+        0x0800_0102 => Some(0xf8f0),
+        0x0800_0202 => Some(0x4798),
+        0x0800_0300 => Some(0x3001),
+        0x0800_0302 => Some(0x3001),
+        _ => None,
+    };
+    let stack = [0x0800_0105, 0x2000_0100, 0x0800_0104, 0x0800_0305,
+                 0x0800_0205, 0x1234_5678];
+    let read = |a: u32| stack.get((a - 0x2000_1000) as usize / 4).copied();
+    let mut trace = [0; 4];
+    let n = backtrace(0x2000_1000, &mut trace, read, code);
+    assert_eq!(trace[.. n], [0x0800_0104, 0x0800_0204]);
+}
@@ -0,0 +1,116 @@
+//! Flash erase and programming of the reserved pages, see `record`.
+//!
+//! The CPU stalls while the flash is busy, including interrupts, so an erase
+//! (≈22ms) glitches the display.  We only write on console commands, and at
+//! boot before the display starts, see `boot` and `watchdog`.
+
+use stm32g030::FLASH;
+
+use crate::record::{BASE, Error, PAGE_SIZE, Store};
+
+/// SR error flags: OPTVERR, RDERR, FASTERR, MISSERR, PGSERR, SIZERR, PGAERR,
+/// WRPERR, PROGERR, OPERR.
+const SR_ERRORS: u32 = 0xc3fa;
+
+/// The reserved pages in the flash.
+pub struct Flash;
+
+/// Page number of the first reserved page.
+const FIRST_PAGE: u32 = (BASE - 0x0800_0000) / PAGE_SIZE as u32;
+
+/// Run `f` with the flash unlocked.  Program and erase do not work in LP run
+/// mode, so we leave it for the duration.  The flash times its operations
+/// from HSI16, which it enables itself, so the system clock does not matter.
+pub fn unlocked<T>(f: impl FnOnce(&mut Flash) -> T) -> T {
+    let flash = unsafe {&*FLASH::ptr()};
+    let pwr = unsafe {&*stm32g030::PWR::ptr()};
+
+    pwr.CR1.modify(|_, w| w.LPR().clear_bit());
+    while pwr.SR2.read().REGLPF().bit() {}
+
+    flash.KEYR.write(|w| w.bits(0x45670123));
+    flash.KEYR.write(|w| w.bits(0xcdef89ab));
+    let result = f(&mut Flash);
+    flash.CR.modify(|_, w| w.LOCK().set_bit());
+
+    crate::cpu::clocks();
+    result
+}
+
+/// Wait for an operation to finish, and collect the result.
+fn finish() -> Result<(), Error> {
+    let flash = unsafe {&*FLASH::ptr()};
+    while flash.SR.read().BSY1().bit() {}
+    let sr = flash.SR.read().bits();
+    flash.SR.write(|w| w.bits(sr));     // Clear EOP and errors.
+    if sr & SR_ERRORS != 0 {Err(Error::Flash)} else {Ok(())}
+}
+
+impl Store for Flash {
+    fn page(&self, page: usize) -> &[u8] {
+        let address = BASE as usize + page * PAGE_SIZE;
+        unsafe {core::slice::from_raw_parts(address as *const u8, PAGE_SIZE)}
+    }
+
+    fn erase(&mut self, page: usize) -> Result<(), Error> {
+        let flash = unsafe {&*FLASH::ptr()};
+        finish()?;
+        flash.CR.modify(|_, w| w.PER().set_bit()
+                        .PNB().bits((FIRST_PAGE + page as u32) as u8));
+        flash.CR.modify(|_, w| w.STRT().set_bit());
+        let result = finish();
+        flash.CR.modify(|_, w| w.PER().clear_bit());
+        result
+    }
+
+    fn program(&mut self, page: usize, offset: usize, data: &[u8])
+        -> Result<(), Error> {
+        let flash = unsafe {&*FLASH::ptr()};
+        let mut address = BASE as usize + page * PAGE_SIZE + offset;
+        finish()?;
+        flash.CR.modify(|_, w| w.PG().set_bit());
+        let mut result = Ok(());
+        for chunk in data.chunks(8) {
+            let mut dw = [0xff; 8];
+            dw[.. chunk.len()].copy_from_slice(chunk);
+            let [lo, hi] = [&dw[.. 4], &dw[4 ..]].map(
+                |w| u32::from_le_bytes(w.try_into().unwrap()));
+            // The two words must be written in order, back to back.
+            unsafe {
+                core::ptr::write_volatile(address as *mut u32, lo);
+                core::ptr::write_volatile((address + 4) as *mut u32, hi);
+            }
+            result = finish();
+            if result.is_err() {
+                break;
+            }
+            address += 8;
+        }
+        flash.CR.modify(|_, w| w.PG().clear_bit());
+        result
+    }
+}
+
+/// Clear bits in the option bytes, if any are set, and reload them.  The
+/// reload resets the device, so this only returns if there is nothing to do,
+/// or on an error.
+pub fn clear_options(mask: u32) -> Result<(), Error> {
+    let flash = unsafe {&*FLASH::ptr()};
+    let optr = flash.OPTR.read().bits();
+    if optr & mask == 0 {
+        return Ok(());
+    }
+    unlocked(|_| {
+        flash.OPTKEYR.write(|w| w.bits(0x08192a3b));
+        flash.OPTKEYR.write(|w| w.bits(0x4c5d6e7f));
+        finish()?;
+        flash.OPTR.write(|w| w.bits(optr & !mask));
+        flash.CR.modify(|_, w| w.OPTSTRT().set_bit());
+        finish()?;
+        flash.CR.modify(|_, w| w.OBL_LAUNCH().set_bit());
+        Ok(())
+    })
+}
+
+/// The reserved pages, for reading.
+pub fn store() -> &'static Flash {&Flash}
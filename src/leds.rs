@@ -75,6 +75,79 @@ pub static LEDS: [u8; 36] = [
     A +  5, A +  6, A + 7, B +  0, B +  1, B +  2,
 ];
 
+/// LEDs grouped by brightness correction, one group per TIM3 compare
+/// channel.
+#[derive(Clone, Copy)]
+pub struct Groups {
+    /// Brightness of each group, as in `LedCorr`.  The first is the highest.
+    pub levels: [u8; 4],
+    /// GPIO bit masks of each group.
+    pub masks: [u64; 4],
+}
+
+/// Quantize the per LED correction into four groups.  Up to four distinct
+/// values are used exactly.  Otherwise we split the range into four equal
+/// bands, and use the mean of each band.  Empty bands get the highest level,
+/// so that they coincide with CC1.
+pub fn groups(corr: &[u8; 36]) -> Groups {
+    let max = *corr.iter().max().unwrap() as u32;
+    let min = *corr.iter().min().unwrap() as u32;
+    let mut distinct = [0u8; 4];
+    let mut num_distinct = 0;
+    for &c in corr {
+        if !distinct[.. num_distinct.min(4)].contains(&c) {
+            if num_distinct < 4 {
+                distinct[num_distinct] = c;
+            }
+            num_distinct += 1;
+        }
+    }
+    distinct[.. num_distinct.min(4)].sort_unstable_by(|a, b| b.cmp(a));
+
+    let mut sums = [0u32; 4];
+    let mut counts = [0u32; 4];
+    let mut masks = [0; 4];
+    for (&c, &led) in corr.iter().zip(&LEDS) {
+        let band = if num_distinct <= 4 {
+            distinct.iter().position(|&d| d == c).unwrap()
+        }
+        else {
+            ((max - c as u32) * 4 / (max - min + 1)) as usize
+        };
+        sums[band] += c as u32;
+        counts[band] += 1;
+        masks[band] |= 1 << led;
+    }
+    let levels = core::array::from_fn(|i| match counts[i] {
+        0 => max as u8,
+        n => ((sums[i] + n / 2) / n) as u8,
+    });
+    Groups {levels, masks}
+}
+
+/// Console: `corr [led [level]]`, the brightness correction of an LED, 0 to
+/// 35 in `LEDS` order, 255 for full brightness.
+pub fn cmd_corr(args: &mut crate::console::Args) {
+    let config = unsafe {crate::config::CONFIG.as_mut()};
+    let Some(led) = args.number() else {
+        for row in config.led_corr.0.chunks(6) {
+            stm_common::dbgln!("{row:?}");
+        }
+        return;
+    };
+    let level = args.number();
+    if !(0 .. 36).contains(&led) || !args.done()
+        || level.is_some_and(|l| !(0 ..= 255).contains(&l)) {
+        stm_common::dbgln!("corr [0..36 [0..=255]]");
+        return;
+    }
+    if let Some(level) = level {
+        config.led_corr.0[led as usize] = level as u8;
+        crate::pulse::set_groups(groups(&config.led_corr.0));
+    }
+    stm_common::dbgln!("corr {led} {}", config.led_corr.0[led as usize]);
+}
+
 #[test]
 fn unique() {
     let mut leds = LEDS;
@@ -89,6 +162,30 @@ fn bit_counts() {
     assert_eq!(LED_ALL.count_ones(), 36);
 }
 
+#[test]
+fn quantize() {
+    // No correction, everything in the first group.
+    let g = groups(&[255; 36]);
+    assert_eq!(g.levels, [255; 4]);
+    assert_eq!(g.masks, [LED_ALL, 0, 0, 0]);
+
+    // Up to four distinct values are exact.
+    let corr = core::array::from_fn(|i| [255, 200, 128, 100][i % 4]);
+    let g = groups(&corr);
+    assert_eq!(g.levels, [255, 200, 128, 100]);
+    assert_eq!(g.masks.iter().fold(0, |a, m| a | m), LED_ALL);
+    assert_eq!(g.masks.map(|m| m.count_ones()), [9; 4]);
+
+    // A spread is approximated by the band means.
+    let corr = core::array::from_fn(|i| 220 + i as u8);
+    let g = groups(&corr);
+    assert_eq!(g.levels, [251, 242, 233, 224]);
+    for (i, &c) in corr.iter().enumerate() {
+        let band = g.masks.iter().position(|m| m & 1 << LEDS[i] != 0).unwrap();
+        assert!(c.abs_diff(g.levels[band]) <= 4);
+    }
+}
+
 #[test]
 fn sane_columns() {
     for column in COLUMNS {
@@ -0,0 +1,123 @@
+//! Deferred formatting log.
+//!
+//! `log!(MODULE, "format", args...)` interns the format string in the
+//! `.logstr` section, which is kept in the ELF file but not loaded, and
+//! sends just its offset and the arguments over the debug UART.  So a log
+//! call costs a few words of flash, and a few bytes on the wire, and
+//! `blinky-log` in `tools/` puts the text back together from `blinky.elf`.
+//!
+//! Arguments are integers.  The placeholders are `{}`, `{:?}`, `{:x}`,
+//! `{:#x}`, and `{:blocks}` which shows a `u64` display as block graphics.
+//!
+//! A frame is the string offset, a byte with a bit set for each signed
+//! argument, then the arguments, zigzag encoded if signed, all as LEB128.
+//! This is sent as raw bytes, `\x1e`, the length and then the frame, so that
+//! it can be mixed in with the text output, and no `core::fmt` is involved.
+
+/// Per module enables.
+pub const ADC: bool = false;
+pub const PENDSV: bool = false;
+
+/// Most arguments in one log call.
+pub const MAX_ARGS: usize = 8;
+
+/// A log argument, and whether it is signed.
+#[derive(Clone, Copy)]
+pub struct Arg(u64, bool);
+
+macro_rules! arg {($($t:ty: $signed:literal),*) => {$(
+    impl From<$t> for Arg {
+        fn from(v: $t) -> Arg {Arg(v as u64, $signed)}
+    }
+)*}}
+
+arg!(u8: false, u16: false, u32: false, u64: false, usize: false,
+     bool: false, i8: true, i16: true, i32: true);
+
+/// Log, if the module is enabled.
+macro_rules! log {($enable:ident, $fmt:literal $(, $a:expr)* $(,)?) => {
+    if crate::log::$enable && crate::DEBUG_ENABLE {
+        const FMT: &str = concat!($fmt, "\0");
+        const _: () = assert!(crate::log::placeholders(FMT) == {
+            let args: &[&str] = &[$(stringify!($a)),*];
+            args.len()});
+        #[unsafe(link_section = ".logstr")]
+        static STR: [u8; FMT.len()] = *FMT.as_bytes().first_chunk().unwrap();
+        crate::log::write((&raw const STR) as usize,
+                          &[$(crate::log::Arg::from($a)),*]);
+    }
+}}
+
+pub(crate) use log;
+
+/// Number of placeholders in a format string, checked against the arguments
+/// at compile time.
+pub const fn placeholders(fmt: &str) -> usize {
+    let fmt = fmt.as_bytes();
+    let mut n = 0;
+    let mut i = 0;
+    while i < fmt.len() {
+        if fmt[i] == b'{' {
+            if i + 1 < fmt.len() && fmt[i + 1] == b'{' {
+                i += 1;
+            }
+            else {
+                n += 1;
+            }
+        }
+        i += 1;
+    }
+    assert!(n <= MAX_ARGS);
+    n
+}
+
+fn leb128(buf: &mut [u8], len: &mut usize, v: u64) {
+    let mut v = v;
+    while v >= 0x80 {
+        buf[*len] = v as u8 | 0x80;
+        *len += 1;
+        v >>= 7;
+    }
+    buf[*len] = v as u8;
+    *len += 1;
+}
+
+/// Longest frame: the string offset, the signed flags and the arguments.
+const FRAME_MAX: usize = 3 + 1 + MAX_ARGS * 10;
+
+/// Encode a frame into `buf`, at least `FRAME_MAX` long, returning the length.
+pub fn encode(buf: &mut [u8], id: usize, args: &[Arg]) -> usize {
+    let mut len = 0;
+    leb128(buf, &mut len, id as u64);
+    if !args.is_empty() {
+        let signed = args.iter().rev().fold(0, |m, a| m << 1 | a.1 as u8);
+        buf[len] = signed;
+        len += 1;
+    }
+    for &Arg(v, signed) in args {
+        // Sign extend, then zigzag.
+        let v = if signed {(v << 1) ^ ((v as i64 >> 63) as u64)} else {v};
+        leb128(buf, &mut len, v);
+    }
+    len
+}
+
+/// Send a log frame, called by `log!`.
+pub fn write(id: usize, args: &[Arg]) {
+    let mut frame = [0; 2 + FRAME_MAX];
+    let len = encode(&mut frame[2 ..], id, args);
+    frame[0] = 0x1e;
+    frame[1] = len as u8;
+    crate::debug::write_raw(&frame[.. len + 2]);
+}
+
+#[test]
+fn frames() {
+    assert_eq!(placeholders("a {} {{}} {:#x}\0"), 2);
+    let mut buf = [0; FRAME_MAX];
+    let len = encode(&mut buf, 300, &[Arg::from(5u8), Arg::from(-2i32),
+                                      Arg::from(1u64 << 63)]);
+    assert_eq!(buf[.. len],
+               [0xac, 0x02, 0b010, 5, 3,
+                0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x01]);
+}
@@ -0,0 +1,131 @@
+//! User messages, stored in flash, see `record::Message`.  When there are
+//! stored main or exception messages, they replace the built-in ones of the
+//! season.
+
+use stm_common::vcell::UCell;
+
+use crate::chars::{find_char, map_bytes};
+use crate::console::Args;
+use crate::marque::{marque_string, rmarque_string};
+use crate::pendsv::FIFTH;
+use crate::record::{KIND_MESSAGES, MESSAGES_LEN, MESSAGES_VERSION, Message,
+                    Messages, Store};
+use crate::select::{MAX, Mode, Selector};
+
+/// The stored messages.
+pub fn stored() -> Messages<'static> {
+    let store = crate::flash::store();
+    let page = crate::record::active(store);
+    match crate::record::latest(store.page(page), KIND_MESSAGES) {
+        Some(record) => Messages(record.payload),
+        None => Messages(&[]),
+    }
+}
+
+/// Choosers for the exception and main messages.
+static SELECTORS: UCell<[Selector; 2]> =
+    UCell::new([Selector::NEW, Selector::NEW]);
+
+/// Choose a stored message by weight, not repeating the last.
+fn choose(main: bool) -> Option<Message<'static>> {
+    let mut weights = [0; MAX];
+    let n = weights.iter_mut().zip(stored().filter(|m| m.main == main))
+        .map(|(w, m)| *w = m.weight).count();
+    let selector = unsafe {&mut SELECTORS.as_mut()[main as usize]};
+    let i = selector.choose(
+        Mode::Weighted, &weights[.. n], &crate::random::RANDOM)?;
+    stored().filter(|m| m.main == main).nth(i)
+}
+
+/// Map a message to the display, with a trailing space like the built-ins.
+fn map(text: &[u8], buf: &mut [u8; 257]) -> usize {
+    let len = text.len();
+    buf[.. len].copy_from_slice(text);
+    buf[len] = b' ';
+    map_bytes(&mut buf[.. len + 1]);
+    len + 1
+}
+
+/// Show a stored main message, returning false if there are none.
+pub fn show_main() -> bool {
+    let Some(message) = choose(true) else {return false};
+    let mut buf = [0; 257];
+    let len = map(message.text, &mut buf);
+    marque_string(&mut 0, &buf[.. len], FIFTH);
+    true
+}
+
+/// Show a stored exception message, returning false if there are none.
+pub fn show_exception() -> bool {
+    let Some(message) = choose(false) else {return false};
+    let mut buf = [0; 257];
+    let len = map(message.text, &mut buf);
+    rmarque_string(&buf[.. len], FIFTH);
+    true
+}
+
+/// Write a new list of messages, built by `f` from the current ones.
+fn rewrite(f: impl FnOnce(&mut [u8], &mut usize) -> bool) {
+    let mut buf = [0; MESSAGES_LEN];
+    let mut len = 0;
+    if !f(&mut buf, &mut len) {
+        stm_common::dbgln!("msg full");
+        return;
+    }
+    let result = crate::flash::unlocked(|flash| crate::record::append(
+        flash, KIND_MESSAGES, MESSAGES_VERSION, &buf[.. len]));
+    stm_common::dbgln!("msg {:?}", result);
+}
+
+/// Console: `msg [add main|ex <weight> <text>|del <n>|clear]`.
+pub fn cmd_msg(args: &mut Args) {
+    match args.word() {
+        b"" => for (i, m) in stored().enumerate() {
+            let text = core::str::from_utf8(m.text).unwrap_or("?");
+            let kind = if m.main {"main"} else {"ex"};
+            stm_common::dbgln!("{i} {kind} {} {text}", m.weight);
+        },
+        b"add" => {
+            let main = match args.word() {
+                b"main" => true,
+                b"ex" => false,
+                _ => return usage(),
+            };
+            let weight = args.number();
+            let Some(weight) = weight.filter(|w| (1 ..= 255).contains(w)) else {
+                return usage()};
+            let mut text = [0; crate::console::LINE_LEN];
+            let rest = args.rest();
+            if rest.is_empty() {
+                return usage();
+            }
+            let text = &mut text[.. rest.len()];
+            text.copy_from_slice(rest);
+            text.make_ascii_uppercase();
+            if let Some(&c) = text.iter().find(|&&c| find_char(c).is_none()) {
+                stm_common::dbgln!("msg bad char {:?}", c as char);
+                return;
+            }
+            let text = &*text;
+            let new = Message {main, weight: weight as u8, text};
+            rewrite(|buf, len| stored().chain([new])
+                    .all(|m| m.encode(buf, len)));
+        },
+        b"del" => {
+            let Some(n) = args.number() else {return usage()};
+            if n < 0 || n as usize >= stored().count() {
+                stm_common::dbgln!("msg no {n}");
+                return;
+            }
+            rewrite(|buf, len| stored().enumerate()
+                    .filter(|&(i, _)| i as i32 != n)
+                    .all(|(_, m)| m.encode(buf, len)));
+        },
+        b"clear" => rewrite(|_, _| true),
+        _ => usage(),
+    }
+}
+
+fn usage() {
+    stm_common::dbgln!("msg [add main|ex <weight> <text>|del <n>|clear]");
+}
@@ -0,0 +1,165 @@
+//! LED current model for constant brightness.
+//!
+//! Each LED is driven from Vdd through a series resistor, so the on current is
+//! (Vdd - Vf) / R.  The brightness follows the average current, which is the
+//! on current times the duty cycle.  For a target average current, the duty
+//! is therefore inversely proportional to (Vdd - Vf).
+//!
+//! A linear ramp in ADC counts (what we used to do) cannot follow this: for
+//! white LEDs with Vf close to Vdd the error exceeds 100% mid-range.  See the
+//! `simulate` test, which sweeps Vdd and reports the brightness error.
+//!
+//! The supply also has a source resistance, so the more LEDs are lit, the
+//! more Vdd droops while they are on.  We measure Vdd with the LEDs off, so
+//! `frame_duty` compensates for this per frame, from the number of lit LEDs.
+
+use crate::config::{Config, DUTY_MAX, PWM_MIN};
+use crate::console::Args;
+use crate::pulse::PWM_DIV;
+
+/// Measured LED parameters for a board.
+#[derive(Clone, Copy)]
+pub struct Led {
+    /// Forward voltage at the operating current.
+    pub vf_mv: u32,
+    /// Series resistor.
+    pub ohms: u32,
+    /// Target average current per LED, in µA.
+    pub target_ua: u32,
+}
+
+impl Led {
+    /// The duty × mV product, such that duty = pwm_scale / (Vdd - Vf) gives
+    /// the target current.
+    pub const fn pwm_scale(&self) -> u32 {
+        let scale = self.target_ua as u64 * self.ohms as u64 * PWM_DIV as u64
+            / 1000;
+        assert!(scale <= u32::MAX as u64);
+        scale as u32
+    }
+
+    /// Average current in µA for a given duty and Vdd.
+    pub const fn current_ua(&self, duty: u32, vdd_mv: u32) -> u32 {
+        if vdd_mv <= self.vf_mv {
+            return 0;
+        }
+        ((vdd_mv - self.vf_mv) as u64 * 1000 * duty as u64
+         / (self.ohms as u64 * PWM_DIV as u64)) as u32
+    }
+}
+
+/// Adjust the duty for a frame with `lit` LEDs, given the Vdd measured with
+/// the LEDs off.
+///
+/// With source resistance Rs, and n LEDs lit, each LED sees (Vdd - Vf) scaled
+/// by R / (R + n·Rs), so we scale the duty by the inverse.  Then, if
+/// configured, limit the average current for the frame to `current_cap`.
+pub const fn frame_duty(config: &Config, duty: u32, lit: u32, vdd_mv: u32)
+    -> u32
+{
+    let ohms = config.led_ohms as u64;
+    let load_ohms = ohms + lit as u64 * config.source_ohms as u64;
+    let mut duty = duty as u64 * load_ohms / ohms;
+
+    let vf_mv = config.vf_mv as u32;
+    if config.current_cap != 0 && lit != 0 && vdd_mv > vf_mv {
+        let max = config.current_cap as u64 * load_ohms * PWM_DIV as u64
+            / (lit as u64 * (vdd_mv - vf_mv) as u64 * 1000);
+        if duty > max {
+            duty = max;
+        }
+    }
+    if duty < PWM_MIN as u64 {PWM_MIN}
+    else if duty > DUTY_MAX as u64 {DUTY_MAX}
+    else {duty as u32}
+}
+
+/// Console: `load [ohms [cap]]`, the source resistance and the current cap in
+/// µA.
+pub fn cmd_load(args: &mut Args) {
+    if let Some(ohms) = args.number() {
+        let cap = args.number().unwrap_or(0);
+        if !(0 ..= 1000).contains(&ohms) || !(0 ..= 65535).contains(&cap)
+            || !args.done() {
+            stm_common::dbgln!("load 0..=1000 [0..=65535]");
+            return;
+        }
+        let config = unsafe {crate::config::CONFIG.as_mut()};
+        config.source_ohms = ohms as u16;
+        config.current_cap = cap as u16;
+    }
+    let config = crate::config::get();
+    stm_common::dbgln!("load {}Ω cap {}µA", config.source_ohms,
+                       config.current_cap);
+}
+
+#[test]
+fn load_compensation() {
+    let mut config = Config::new(&crate::config::WHITE);
+    // No source resistance or cap, no change.
+    assert_eq!(frame_duty(&config, 1000, 36, 3000), 1000);
+
+    config.source_ohms = 10;
+    assert_eq!(frame_duty(&config, 1000, 0, 3000), 1000);
+    assert_eq!(frame_duty(&config, 1000, 1, 3000), 1030);
+    assert_eq!(frame_duty(&config, 1000, 36, 3000), 2090);
+    // The brightness per LED is the same however many are lit.
+    let led = &crate::config::WHITE;
+    let target = led.current_ua(1000, 3000);
+    for lit in [1, 4, 16, 36] {
+        let duty = frame_duty(&config, 1000, lit, 3000);
+        // Vdd droops under load.
+        let vdd = led.vf_mv + (3000 - led.vf_mv) * led.ohms
+            / (led.ohms + lit * config.source_ohms as u32);
+        let ua = led.current_ua(duty, vdd);
+        assert!(ua.abs_diff(target) <= 1, "{lit} {ua} {target}");
+    }
+
+    // 2mA cap.  36 LEDs at (3000 - 2750) / 690Ω is 13mA on.
+    config.current_cap = 2000;
+    assert_eq!(frame_duty(&config, 1000, 36, 3000), 479);
+    assert_eq!(frame_duty(&config, 1000, 1, 3000), 1030);
+}
+
+#[test]
+fn simulate() {
+    /// The old linear ramp in ADC counts, with the hand picked PWM_MAX.
+    fn linear(vdd_mv: u32, pwm_max: u32) -> u32 {
+        const CAL: u32 = 1655;          // Typical VREFINT_CAL.
+        const OVER3: u32 = 273;
+        const UNDER3: u32 = 273;
+        let counts = CAL * 3000 / vdd_mv;
+        let top = CAL + OVER3;
+        let max = OVER3 + UNDER3;
+        let delta = if top > counts {(top - counts).min(max)} else {0};
+        ((pwm_max - PWM_MIN) * 65536).div_ceil(max) * delta / 65536 + PWM_MIN
+    }
+
+    for (name, led, pwm_max) in [
+        ("white", crate::config::WHITE, PWM_DIV / 2),
+        ("red", crate::config::RED, PWM_DIV / 4)]
+    {
+        let config = Config::new(&led);
+        println!("{name}: Vf {}mV, target {}µA", led.vf_mv, led.target_ua);
+        println!("  Vdd  duty    µA error  linear    µA error");
+        for vdd_mv in (2000 ..= 3400).step_by(100) {
+            let duty = config.calc_duty(vdd_mv);
+            let ua = led.current_ua(duty, vdd_mv);
+            let error = ua as f64 / led.target_ua as f64 - 1.0;
+            let old = linear(vdd_mv, pwm_max);
+            let old_ua = led.current_ua(old, vdd_mv);
+            let old_error = old_ua as f64 / led.target_ua as f64 - 1.0;
+            println!(
+                "  {vdd_mv} {duty:5} {ua:5} {:4.0}% {old:7} {old_ua:5} {:4.0}%",
+                error * 100.0, old_error * 100.0);
+            // Within the duty limits, we should be spot on.
+            if duty > PWM_MIN && duty < DUTY_MAX {
+                assert!(error.abs() < 0.01, "{name} {vdd_mv} {error}");
+            }
+            else {
+                assert!(duty == PWM_MIN && error > 0.0
+                        || duty == DUTY_MAX && error < 0.0);
+            }
+        }
+    }
+}
@@ -1,5 +1,11 @@
 use stm_common::vcell::{UCell, VCell};
 
+use crate::log::log;
+use crate::pulse::Frame;
+use crate::trace::Event;
+
+/// Block graphics for the display, used by the `blinky-log` decoder.
+#[cfg(test)]
 mod text;
 
 /// Number of application wake-ups per second.
@@ -16,9 +22,7 @@ static COUNT: VCell<i32> = VCell::new(0);
 static APP_COUNT: VCell<i32> = VCell::new(0);
 
 /// LEDs to display next tick.
-static NEXT_LEDS: UCell<u64> = UCell::new(0);
-
-macro_rules! dbgln {($($tt: tt)*) => {if false {stm_common::dbgln!($($tt)*)}}}
+static NEXT_LEDS: UCell<Frame> = UCell::new(Frame::BLANK);
 
 pub fn init() {
     // We use the PENDSV exception to dispatch some work at lower priority.
@@ -50,15 +54,11 @@ pub fn set_display(display: u64) {
         leds |= crate::leds::COLUMNS[i][d as usize & 0x3f];
         d >>= 8;
     }
+    let frame = Frame::new(leds);
     stm_common::interrupt::disable_all();
-    *unsafe {NEXT_LEDS.as_mut()} = leds;
+    *unsafe {NEXT_LEDS.as_mut()} = frame;
     stm_common::interrupt::enable_all();
-    if crate::DEBUG_ENABLE {
-        let strings = text::blocks(display);
-        dbgln!("{}{}{}\n{}{}{}",
-               strings[0], strings[1], strings[2],
-               strings[3], strings[4], strings[5]);
-    }
+    log!(PENDSV, "{:blocks}", display);
 }
 
 #[cold]
@@ -69,16 +69,20 @@ pub fn trigger() {
 
 pub fn sleep(wait: u32) {
     static ALLOC: UCell<i32> = UCell::new(0);
+    crate::console::poll();
     let target = ALLOC.wrapping_add(wait as i32);
     unsafe {*ALLOC.as_mut() = target};
-    dbgln!("Sleep for {target}");
+    log!(PENDSV, "Sleep for {}", target);
     while APP_COUNT.read().wrapping_sub(target) < 0 {
+        crate::watchdog::alive();
         stm_common::utils::WFE();
     }
-    dbgln!("Wakes");
+    log!(PENDSV, "Wakes");
 }
 
 fn pendsv_handler() {
+    let now = crate::trace::now();
+    let cc1 = crate::trace::cc1();
     static ALLOC: UCell<i32> = UCell::new(0);
     let alloc = unsafe {ALLOC.as_mut()};
     // We loop just in case we miss a tick.  Or get a spurious wake-up.
@@ -92,13 +96,14 @@ fn pendsv_handler() {
             1 => {
                 // Trigger the app.
                 APP_COUNT.write(APP_COUNT.read().wrapping_add(1));
+                crate::watchdog::tick();
             }
             0|ADC2 => {
                 crate::adc::power_up();
                 // We are already past the point in the tick where we use the
                 // LED setting.  So set the next one.
                 if phase == 0 {
-                    crate::pulse::apply_leds(*NEXT_LEDS);
+                    crate::pulse::apply_leds(&NEXT_LEDS);
                 }
                 // Run the ADC conversion.
                 crate::adc::start();
@@ -106,6 +111,8 @@ fn pendsv_handler() {
             _ => ()
         }
     }
+    crate::trace::record(Event::PendsvEntry, now, cc1);
+    crate::trace::record(Event::PendsvExit, crate::trace::now(), cc1);
 }
 
 impl crate::cpu::Config {
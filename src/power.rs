@@ -0,0 +1,147 @@
+//! Low power modes.
+//!
+//! When the display is on, the CPU sits in WFE between the PWM interrupts.
+//! At 2MHz and below we are in LP run, so that is LP sleep.  The options to
+//! do better, selected by `cpu::Config` builders and both on in `CONFIG`,
+//! are:
+//!
+//! * `flash_powerdown`: power the flash down in LP sleep and STOP.  This
+//!   adds the flash wake-up time to every interrupt, which `trace` shows.
+//! * `sleep_clocks`: gate the clocks of everything but TIM3, the ADC and the
+//!   debug UART while asleep.  The GPIOs hold their outputs without a clock.
+//!
+//! Options looked at and not done:
+//!
+//! * There is no LPTIM on the G030.  TIM3 outputs only reach fixed pins,
+//!   and the LEDs are a matrix over GPIO ports A to D.  Driving the
+//!   BRR/BSRR writes from TIM3 by DMA needs a channel per port per edge, at
+//!   least eight, and there are five.
+//! * STOP between pulses stops TIM3.  The only timebase left is the RTC
+//!   wake-up timer, at 61µs resolution from an LSI good to a few percent,
+//!   against the 4µs PWM clock, so the lowest brightness steps would go.
+//!   The debug UART also can't receive in STOP from PCLK.
+//! * A lower HSI divider: 125kHz gives 1562.5 PWM clocks per 80Hz cycle,
+//!   which the `pulse` asserts reject.
+//!
+//! Current budget for the MCU alone, with the LEDs, which dominate when lit,
+//! excluded.  It is worked out from the datasheet typical figures at 25°C,
+//! for the modes above; the options not done have no entry.
+//!
+//! | Mode                                  | Current  |
+//! |---------------------------------------|----------|
+//! | LP run, 250kHz, from flash            | ≈100µA   |
+//! | LP sleep between pulses, no options   | ≈40µA    |
+//! | ... with `sleep_clocks`               | ≈30µA    |
+//! | ... with `flash_powerdown` too (used) | ≈20µA    |
+//! | STOP 1 with the RTC, display off      | ≈5µA     |
+//! | STANDBY, `shutdown`                   | <1µA     |
+
+use crate::CONFIG;
+use crate::leds::PORT_BITS;
+
+/// PWR_CR1.LPMS value for STOP 1.
+const LPMS_STOP1: u8 = 1;
+/// PWR_CR1.LPMS value for STANDBY.
+const LPMS_STANDBY: u8 = 3;
+
+/// SCB_SCR.SLEEPDEEP.
+const SLEEPDEEP: u32 = 1 << 2;
+
+/// RCC sleep mode clock enables we keep.
+const TIM3SMEN: u32 = 1 << 1;
+const DBGSMEN: u32 = 1 << 27;
+const USART1SMEN: u32 = 1 << 14;
+const ADCSMEN: u32 = 1 << 20;
+
+/// Set up the sleep modes, called from `cpu::init`.
+pub fn init() {
+    let pwr = unsafe {&*stm32g030::PWR::ptr()};
+    let rcc = unsafe {&*stm32g030::RCC::ptr()};
+    rcc.APBENR1.modify(|_, w| w.PWREN().set_bit());
+    if CONFIG.flash_powerdown {
+        pwr.CR1.modify(|_, w| w.FPD_SLP().set_bit().FPD_STOP().set_bit());
+    }
+    if CONFIG.sleep_clocks {
+        let debug = if crate::DEBUG_ENABLE {DBGSMEN} else {0};
+        let usart = if crate::DEBUG_ENABLE {USART1SMEN} else {0};
+        rcc.IOPSMENR.write(|w| w.bits(0));
+        rcc.AHBSMENR.write(|w| w.bits(0));
+        rcc.APBSMENR1.write(|w| w.bits(TIM3SMEN | debug));
+        rcc.APBSMENR2.write(|w| w.bits(ADCSMEN | usart));
+    }
+}
+
+/// Turn off all the LEDs.  They are negative logic, so drive the GPIOs high.
+pub fn leds_off() {
+    for i in 0 .. 4 {
+        crate::leds::gpio(i).BSRR.write(|w| w.bits(PORT_BITS[i]));
+    }
+}
+
+/// Blank the display and enter STOP mode for (up to) `seconds`.  Wake-up may
+/// be early on other interrupts, or to feed the watchdog.  Returns with the
+/// PWM still stopped, so that the caller only restarts it, with
+/// `pulse::start`, once the display is on.
+pub fn stop(seconds: u32) {
+    let pwr = unsafe {&*stm32g030::PWR::ptr()};
+    let scb = unsafe {&*cortex_m::peripheral::SCB::PTR};
+
+    crate::pulse::stop();
+    leds_off();
+    if crate::DEBUG_ENABLE {
+        stm_common::debug::flush::<crate::debug::DebugMeta>();
+    }
+
+    crate::rtc::wakeup_after(crate::watchdog::stop(seconds));
+    pwr.CR1.modify(|_, w| w.LPMS().bits(LPMS_STOP1));
+    unsafe {scb.scr.modify(|r| r | SLEEPDEEP)};
+    #[cfg(target_arch = "arm")]
+    cortex_m::asm::wfi();
+    unsafe {scb.scr.modify(|r| r & !SLEEPDEEP)};
+
+    // We wake-up on HSI16, with the divider preserved, but redo the set-up
+    // for LP run.
+    crate::cpu::clocks();
+    crate::watchdog::wake();
+}
+
+impl crate::cpu::Config {
+    /// Power the flash down in LP sleep and STOP.
+    pub const fn flash_powerdown(&mut self) -> &mut Self {
+        self.flash_powerdown = true;
+        self
+    }
+    /// Gate unused peripheral clocks in sleep.
+    pub const fn sleep_clocks(&mut self) -> &mut Self {
+        self.sleep_clocks = true;
+        self
+    }
+}
+
+/// Shut down until reset, for when the battery is exhausted.  We use STANDBY,
+/// with pull-ups holding the LEDs off, and no wake-up sources.
+pub fn shutdown() -> ! {
+    let pwr = unsafe {&*stm32g030::PWR::ptr()};
+    let scb = unsafe {&*cortex_m::peripheral::SCB::PTR};
+
+    stm_common::interrupt::disable_all();
+    crate::pulse::stop();
+    leds_off();
+    crate::rtc::wakeup_disable();
+    // If the watchdog isn't frozen in STANDBY, it will wake us, see
+    // `boot::init`.
+    crate::watchdog::longest();
+
+    pwr.PUCRA.write(|w| w.bits(PORT_BITS[0]));
+    pwr.PUCRB.write(|w| w.bits(PORT_BITS[1]));
+    pwr.PUCRC.write(|w| w.bits(PORT_BITS[2]));
+    pwr.PUCRD.write(|w| w.bits(PORT_BITS[3]));
+    pwr.CR3.write(|w| w.APC().set_bit());
+    pwr.SCR.write(|w| w.bits(!0));      // Clear wake-up flags.
+    pwr.CR1.modify(|_, w| w.LPMS().bits(LPMS_STANDBY));
+    unsafe {scb.scr.modify(|r| r | SLEEPDEEP)};
+    loop {
+        #[cfg(target_arch = "arm")]
+        cortex_m::asm::wfi();
+    }
+}
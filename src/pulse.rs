@@ -2,35 +2,58 @@
 //! capture/compare registers.  The LEDs are PWM'd in two banks.
 //!  We drive the LEDs at approx 80Hz with 3125 PWM clocks per PWM cycle.
 //!
+//! The update turns the lit LEDs on.  CC1 turns everything off, at the duty
+//! for the brightest group of LEDs, see `leds::groups`.  CC2 to CC4 turn the
+//! other groups off earlier.
+//!
 //! Double buffering is used.
 
-use stm_common::vcell::UCell;
+use stm_common::vcell::{UCell, VCell};
 use stm32g030::TIM3 as TIM;
 use stm32g030::Interrupt::TIM3 as INTERRUPT;
 
-use crate::leds::LED_ALL;
+use crate::cpu::PWM_CLK;
+use crate::leds::{Groups, LED_ALL};
+use crate::trace::Event;
 
 // Number of PWM pulses per second.
 pub const RATE: u32 = 80;
 
-/// Prescaler for the PWM timer.
-const PWM_PRESCALE: u32 = (crate::CONFIG.clk / 250_000).max(1);
-/// Number of PWM clocks per pwm cycle.
-pub const PWM_DIV: u32 = crate::CONFIG.clk / PWM_PRESCALE / RATE;
+/// Number of PWM clocks per pwm cycle.  The PWM clock is the same at every
+/// system clock, see `cpu::Clock`.
+pub const PWM_DIV: u32 = PWM_CLK / RATE;
 
-const _: () = assert!(PWM_PRESCALE <= 65536);
 const _: () = assert!(PWM_DIV <= 65536);
-const _: () = assert!(RATE * PWM_DIV * PWM_PRESCALE == crate::CONFIG.clk);
+const _: () = assert!(RATE * PWM_DIV == PWM_CLK);
 const _: () = assert!(PWM_DIV >= 500);
 
 // #[derive_const(Default)]
 #[derive(Copy, Clone)]
 struct Leds {
     leds: [u16; 4],
+    /// GPIO masks to turn off at CC2 to CC4.
+    off: [u64; 3],
+}
+
+/// A frame, mapped to GPIO masks.
+#[derive(Copy, Clone)]
+pub struct Frame {
+    leds: u64,
+    off: [u64; 3],
 }
 
 /// Currently displaying LEDs.
-static LEDS: UCell<Leds> = UCell::new(Leds{leds: [0; _]});
+static LEDS: UCell<Leds> = UCell::new(Leds{leds: [0; _], off: [0; _]});
+
+/// The brightness correction groups.  All zero so that this is in the BSS,
+/// `init` sets them.
+static GROUPS: UCell<Groups> = UCell::new(Groups {
+    levels: [0; _], masks: [0; _]});
+
+/// Duty from the ADC, before adjusting for the number of lit LEDs.
+static DUTY: VCell<u32> = VCell::new(0);
+/// Number of LEDs lit in the current frame.
+static LIT: VCell<u32> = VCell::new(0);
 
 pub fn init() {
     let rcc = unsafe {&*stm32g030::RCC::PTR};
@@ -38,33 +61,100 @@ pub fn init() {
 
     rcc.APBENR1.modify(|_, w| w.TIM3EN().set_bit());
 
-    tim.PSC.write(|w| w.bits(PWM_PRESCALE - 1));
+    set_prescale(crate::cpu::clock().pwm_prescale);
     tim.ARR.write(|w| w.bits(PWM_DIV - 1));
+    // CC2 to CC4 are enabled by `set_groups` as needed.
     tim.DIER.write(|w| w.UIE().set_bit().CC1IE().set_bit());
     tim.CCMR1_Output().write(
-        |w| w.OC1PE().set_bit().OC1M().bits(1).OC1PE().set_bit());
+        |w| w.OC1PE().set_bit().OC1M().bits(1).OC2PE().set_bit());
+    tim.CCMR2_Output().write(|w| w.OC3PE().set_bit().OC4PE().set_bit());
+
+    set_groups(crate::leds::groups(&crate::config::get().led_corr.0));
     tim.CR1.write(|w| w.CEN().set_bit());
 
     stm_common::interrupt::enable_priority(INTERRUPT, crate::cpu::PRIO_PULSE);
 }
 
+/// Set the prescaler, after a clock change.  It takes effect at the next
+/// update.
+pub fn set_prescale(prescale: u32) {
+    let tim = unsafe {&*TIM::PTR};
+    tim.PSC.write(|w| w.bits(prescale - 1));
+}
+
+/// Stop the PWM, e.g., before STOP mode.  The LEDs are left in whatever state
+/// they were in.
+pub fn stop() {
+    let tim = unsafe {&*TIM::PTR};
+    tim.CR1.write(|w| w.CEN().clear_bit());
+}
+
+/// Restart the PWM after `stop()`.
+pub fn start() {
+    let tim = unsafe {&*TIM::PTR};
+    tim.CR1.write(|w| w.CEN().set_bit());
+}
+
+impl Frame {
+    pub const BLANK: Frame = Frame {leds: 0, off: [0; _]};
+
+    /// Split the GPIO mask of a frame into the correction groups.
+    pub fn new(leds: u64) -> Frame {
+        let masks = &GROUPS.as_ref().masks;
+        Frame {leds, off: [1, 2, 3].map(|i| leds & masks[i])}
+    }
+}
+
 /// Set the LED pattern for future PWM cycles.
-pub fn apply_leds(pos: u64) {
+pub fn apply_leds(frame: &Frame) {
+    let pos = frame.leds;
     let leds = unsafe {LEDS.as_mut()};
     stm_common::interrupt::disable_all();
     leds.leds[0] = pos as u16;
     leds.leds[1] = (pos >> 16) as u16;
     leds.leds[2] = (pos >> 32) as u16;
     leds.leds[3] = (pos >> 48) as u16;
+    leds.off = frame.off;
     stm_common::interrupt::enable_all();
+    LIT.write(pos.count_ones());
+    update_duty();
 }
 
+/// Set the brightness correction groups.  Takes effect from the next frame.
+/// Only the compare interrupts for groups in use are enabled.
+pub fn set_groups(groups: Groups) {
+    let tim = unsafe {&*TIM::PTR};
+    let [_, cc2, cc3, cc4] = groups.masks.map(|m| m != 0);
+    stm_common::interrupt::disable_all();
+    *unsafe {GROUPS.as_mut()} = groups;
+    tim.DIER.modify(
+        |_, w| w.CC2IE().bit(cc2).CC3IE().bit(cc3).CC4IE().bit(cc4));
+    stm_common::interrupt::enable_all();
+    update_duty();
+}
+
+/// Set the duty for a single LED, this gets adjusted per frame for the load.
 pub fn set_duty(duty: u32) {
+    DUTY.write(duty);
+    update_duty();
+}
+
+fn update_duty() {
     let tim = unsafe {&*TIM::PTR};
-    tim.CCR1.write(|w| w.bits(duty));
+    let duty = crate::model::frame_duty(
+        crate::config::get(), DUTY.read(), LIT.read(),
+        crate::battery::BATTERY.millivolts());
+    let levels = GROUPS.as_ref().levels;
+    let [cc1, cc2, cc3, cc4] = levels.map(|l| duty * (l as u32 + 1) / 256);
+    tim.CCR1.write(|w| w.bits(cc1.max(crate::config::PWM_MIN)));
+    tim.CCR2.write(|w| w.bits(cc2));
+    tim.CCR3.write(|w| w.bits(cc3));
+    tim.CCR4.write(|w| w.bits(cc4));
 }
 
 fn isr() {
+    let now = crate::trace::now();
+    let cc1 = crate::trace::cc1();
     let tim = unsafe {&*TIM::PTR};
     let sr = tim.SR.read();
     tim.SR.write(|w| w.bits(!sr.bits()));
@@ -72,10 +162,26 @@ fn isr() {
     if sr.UIF().bit() {
         reset(&LEDS.as_ref().leds);
     }
+    let off = &LEDS.as_ref().off;
+    if sr.CC2IF().bit() {
+        set(off[0], 0);
+    }
+    if sr.CC3IF().bit() {
+        set(off[1], 0);
+    }
+    if sr.CC4IF().bit() {
+        set(off[2], 0);
+    }
     if sr.CC1IF().bit() {
         set(LED_ALL, 0);
         crate::pendsv::trigger();
     }
+    if sr.UIF().bit() {
+        crate::trace::record(Event::Update, now, 0);
+    }
+    if sr.CC1IF().bit() {
+        crate::trace::record(Event::Cc1, now, cc1);
+    }
 }
 
 /// Set LEDs via the 4-GPIO bit mask.  `on` and `off` refer to the GPIO level,
@@ -1,26 +1,184 @@
-//! Poor man's random number generator - efficient on a Cortex-M0+.
+//! Random number generator - xoshiro128**, which is just shifts, rotates and
+//! cheap multiplies, so efficient on a Cortex-M0+.
+//!
+//! Entropy, the ADC LSBs and TIM3 jitter, is stirred into a pool word, which
+//! is folded into the state once, at the next draw.  The state is in
+//! `.noinit` RAM, so it carries on over a reset.  After power on it fails the
+//! check, and is seeded from whatever was in the RAM, and the device unique
+//! ID.
 
 use stm_common::vcell::VCell;
 
-#[derive_const(Default)]
 pub struct Random {
-    state: VCell<u32>
+    state: VCell<[u32; 4]>,
+    /// Entropy from `stir`.
+    pool: VCell<u32>,
+    /// Check of the state, for `.noinit`.
+    check: VCell<u32>,
 }
 
-pub static RANDOM: Random = Random::default();
+#[unsafe(link_section = ".noinit")]
+pub static RANDOM: Random = Random::new(0);
+
+const CHECK_MAGIC: u32 = 0x5eed_cafe;
+const GOLDEN: u32 = 0x9e37_79b9;
+
+/// Murmur3 finalizer, a bijection, for seeding.
+const fn mix(x: u32) -> u32 {
+    let x = (x ^ x >> 16).wrapping_mul(0x85eb_ca6b);
+    let x = (x ^ x >> 13).wrapping_mul(0xc2b2_ae35);
+    x ^ x >> 16
+}
+
+const fn check(s: [u32; 4]) -> u32 {
+    s[0] ^ s[1].rotate_left(8) ^ s[2].rotate_left(16) ^ s[3].rotate_left(24)
+        ^ CHECK_MAGIC
+}
+
+/// State from four words of seed.  `mix` of distinct values gives at most
+/// one zero, so the state is never all zero.
+const fn seeded(seed: [u32; 4]) -> [u32; 4] {
+    let mut s = [0; 4];
+    let mut i = 0;
+    while i < 4 {
+        s[i] = mix(seed[i].wrapping_add(GOLDEN.wrapping_mul(i as u32 + 1)));
+        i += 1;
+    }
+    s
+}
+
+/// Seed at boot, unless the state survived a reset.
+pub fn init() {
+    let state = RANDOM.state.read();
+    if RANDOM.check.read() == check(state) {
+        return;
+    }
+    #[cfg(target_os = "none")]
+    let uid = unsafe {core::ptr::read_volatile(0x1fff_7590 as *const [u32; 3])};
+    #[cfg(not(target_os = "none"))]
+    let uid = [0; 3];
+    RANDOM.pool.write(0);
+    RANDOM.set_state(seeded([state[0] ^ uid[0], state[1] ^ uid[1],
+                             state[2] ^ uid[2], state[3]]));
+}
 
 impl Random {
-    pub fn stir(&self, info: u32) -> u32 {
-        let mut stir = self.state.read();
-        stir = stir << 16 | stir >> 16;
-        stir = stir.wrapping_add(info);
-        stir = stir.wrapping_mul(2654435769);
-        self.state.write(stir);
-        stir
+    /// A generator with a fixed seed.
+    pub const fn new(seed: u64) -> Random {
+        let state = seeded([seed as u32, (seed >> 32) as u32, 0, 0]);
+        Random {
+            state: VCell::new(state), pool: VCell::new(0),
+            check: VCell::new(check(state)),
+        }
+    }
+
+    fn set_state(&self, state: [u32; 4]) {
+        self.state.write(state);
+        self.check.write(check(state));
     }
 
+    /// Add entropy.
+    pub fn stir(&self, info: u32) {
+        let pool = self.pool.read().rotate_left(16).wrapping_add(info);
+        self.pool.write(pool.wrapping_mul(GOLDEN));
+    }
+
+    /// The next 32 random bits.
+    pub fn next(&self) -> u32 {
+        let [mut s0, mut s1, mut s2, mut s3] = self.state.read();
+        // Fold in new entropy, once.  A stir between the read and the write
+        // is lost, which is harmless.
+        let pool = self.pool.read();
+        if pool != 0 {
+            s0 ^= pool;
+            self.pool.write(0);
+        }
+        let result = s1.wrapping_mul(5).rotate_left(7).wrapping_mul(9);
+        let t = s1 << 9;
+        s2 ^= s0;
+        s3 ^= s1;
+        s1 ^= s2;
+        s0 ^= s3;
+        s2 ^= t;
+        s3 = s3.rotate_left(11);
+        self.set_state([s0, s1, s2, s3]);
+        result
+    }
+
+    /// Uniform in `0 .. n`, or 0 if `n` is 0.  Lemire's multiply, rejecting
+    /// the few products that would bias the result.
     pub fn random_n(&self, n: u32) -> u32 {
-        let s = self.stir(0) >> 16;
-        s * n >> 16
+        let mut m = self.next() as u64 * n as u64;
+        if (m as u32) < n {
+            // 2**32 % n, without the u64 divide.
+            let threshold = n.wrapping_neg() % n;
+            while (m as u32) < threshold {
+                m = self.next() as u64 * n as u64;
+            }
+        }
+        (m >> 32) as u32
+    }
+}
+
+#[test]
+fn xoshiro() {
+    // The reference implementation from [1, 2, 3, 4].
+    let r = Random::new(0);
+    r.set_state([1, 2, 3, 4]);
+    assert_eq!(r.next(), 11520);
+    assert_eq!(r.next(), 0);
+    assert_eq!(r.next(), 5927040);
+    // Stirring changes the sequence, from the draw after next.
+    let a = Random::new(1);
+    let b = Random::new(1);
+    b.stir(1);
+    assert_eq!(a.next(), b.next());
+    assert_eq!(b.pool.read(), 0);
+    assert_ne!(a.next(), b.next());
+}
+
+#[test]
+fn chi_square() {
+    // Critical values at 0.1%, for 6 and 15 degrees of freedom.
+    for (n, critical) in [(7, 22.46), (16, 37.70)] {
+        let r = Random::new(12345);
+        const DRAWS: u32 = 70_000;
+        let mut counts = [0u32; 16];
+        for _ in 0 .. DRAWS {
+            counts[r.random_n(n) as usize] += 1;
+        }
+        let expect = DRAWS as f64 / n as f64;
+        let chi2: f64 = counts[.. n as usize].iter()
+            .map(|&c| (c as f64 - expect) * (c as f64 - expect) / expect).sum();
+        assert!(chi2 < critical, "random_n({n}) chi² {chi2}");
+    }
+}
+
+#[test]
+fn ranges() {
+    let r = Random::new(7);
+    assert_eq!(r.random_n(0), 0);
+    assert_eq!(r.random_n(1), 0);
+    // A large range, which a 16-bit multiply-shift leaves with the low bits
+    // clear.
+    let n = 3 << 30;
+    let mut low = 0;
+    let mut odd = 0;
+    for _ in 0 .. 30_000 {
+        let x = r.random_n(n);
+        assert!(x < n);
+        low += (x < 1 << 30) as u32;
+        odd += x & 1;
+    }
+    assert!((9_000 .. 11_000).contains(&low), "{low}");
+    assert!((14_000 .. 16_000).contains(&odd), "{odd}");
+    // Every bit is set about half the time.
+    let mut bits = [0u32; 32];
+    for _ in 0 .. 10_000 {
+        let x = r.next();
+        for (i, b) in bits.iter_mut().enumerate() {
+            *b += x >> i & 1;
+        }
     }
+    assert!(bits.iter().all(|b| (4_700 .. 5_300).contains(b)), "{bits:?}");
 }
@@ -0,0 +1,608 @@
+//! Persistent record format for the reserved flash pages.  This file has no
+//! dependencies on the rest of the firmware, so that host tools can include
+//! it directly.
+//!
+//! The store is two flash pages, used as a log.  Each record is:
+//!
+//! * An 8 byte header: magic, kind, version, payload length and sequence
+//!   number.
+//! * The payload, padded with 0xff to a multiple of 8 bytes.
+//! * 8 bytes holding the CRC32 of the header and payload, then 0xffffffff.
+//!
+//! All values are little endian.  Everything is programmed as whole
+//! double-words, the CRC last, so that a record interrupted by a reset is
+//! ignored.  The latest valid record of each kind in the active page wins.
+//!
+//! When the active page is full, the latest records are copied to the other
+//! page, the new record appended, and then the old page erased.  The active
+//! page is the one holding the highest sequence number, so a reset at any
+//! point leaves us with either the old or the new state.
+
+/// Address of the first reserved page.
+pub const BASE: u32 = 0x0800_7000;
+/// Flash page size.
+pub const PAGE_SIZE: usize = 2048;
+/// Number of reserved pages.
+pub const PAGES: usize = 2;
+
+pub const MAGIC: u16 = 0xb1c5;
+
+/// Record kinds.
+pub const KIND_CONFIG: u8 = 1;
+pub const KIND_MESSAGES: u8 = 2;
+pub const KIND_BOOT: u8 = 3;
+/// Highest kind in use.
+pub const KINDS: u8 = 3;
+
+/// Current version of the config record.  Fields are only ever appended, so
+/// any version decodes: missing fields keep their defaults, and unknown
+/// trailing fields are ignored.
+pub const CONFIG_VERSION: u8 = 3;
+
+/// Current version of the messages record.
+pub const MESSAGES_VERSION: u8 = 1;
+/// Maximum size of the messages record payload.
+pub const MESSAGES_LEN: usize = 512;
+
+/// Current version of the boot counts record.
+pub const BOOT_VERSION: u8 = 1;
+
+const HEADER_LEN: usize = 8;
+const CRC_LEN: usize = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The record does not fit, even after compacting.
+    Full,
+    /// The flash reported an error.
+    Flash,
+}
+
+/// Access to the reserved flash pages.
+pub trait Store {
+    fn page(&self, page: usize) -> &[u8];
+    fn erase(&mut self, page: usize) -> Result<(), Error>;
+    /// Program `data` at a double-word aligned `offset`.  A partial final
+    /// double-word is padded with 0xff.
+    fn program(&mut self, page: usize, offset: usize, data: &[u8])
+        -> Result<(), Error>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Header {
+    pub kind: u8,
+    pub version: u8,
+    pub len: u16,
+    pub seq: u16,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Record<'a> {
+    pub header: Header,
+    /// Offset of the record within the page.
+    pub offset: usize,
+    pub payload: &'a [u8],
+}
+
+/// Iterator over the valid records in a page.
+pub struct Records<'a> {
+    page: &'a [u8],
+    offset: usize,
+    /// Where the next record can be written, valid once the iteration is
+    /// finished.
+    pub end: usize,
+    /// Number of records skipped for a bad CRC.
+    pub skipped: usize,
+}
+
+/// Board config.  The firmware adds its methods in `config`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive_const(Default)]
+pub struct Config {
+    /// LED forward voltage.
+    pub vf_mv: u16,
+    /// Duty × mV, see `model::Led::pwm_scale`.
+    pub pwm_scale: u32,
+    /// LED series resistor.
+    pub led_ohms: u16,
+    /// Supply source resistance, zero for no load compensation, see
+    /// `model::frame_duty`.
+    pub source_ohms: u16,
+    /// Limit on the average LED current in µA, zero for no limit.
+    pub current_cap: u16,
+    /// LSI error in ppm, positive if it runs fast.
+    pub lsi_trim: i32,
+    /// Time of day that the display turns on, in minutes.
+    pub on_start: u16,
+    /// Minutes that the display is on each day, zero for always on.
+    pub on_minutes: u16,
+    /// Message set, 1-based index into `season::SEASONS`, or zero to choose
+    /// by date.
+    pub season: u8,
+    /// Battery low threshold.
+    pub low_mv: u16,
+    /// Battery exhausted threshold, we shut down below this.
+    pub critical_mv: u16,
+    /// Brightness temperature compensation, see `temp::compensate`.
+    pub temp_comp: u16,
+    /// VREFINT calibration from `calibrate`, zero to use the factory value.
+    pub vref_cal: u16,
+    /// Per LED brightness correction.
+    pub led_corr: LedCorr,
+}
+
+/// Brightness of each LED, in `leds::LEDS` order, in 256ths less one, so
+/// that 255 is full brightness.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LedCorr(pub [u8; 36]);
+
+impl const Default for LedCorr {
+    fn default() -> LedCorr {LedCorr([255; 36])}
+}
+
+/// Maximum encoded size of a `Config`.
+pub const CONFIG_LEN: usize = 96;
+
+/// A stored message.  The payload of a messages record is a sequence of
+/// these, each encoded as flags, weight, length and then the ASCII text.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Message<'a> {
+    /// Main message, rather than an exception.
+    pub main: bool,
+    /// Relative weight when choosing a message.
+    pub weight: u8,
+    pub text: &'a [u8],
+}
+
+/// Message flags.
+const MESSAGE_MAIN: u8 = 1;
+
+/// Iterator over the messages in a record payload.  Stops at anything
+/// malformed.
+pub struct Messages<'a>(pub &'a [u8]);
+
+/// Names of the reset causes, in `boot::Cause` order.
+pub const CAUSES: [&str; 8] =
+    ["power", "pin", "soft", "crash", "iwdg", "wwdg", "lowpower", "option"];
+
+/// Number of boots by reset cause, indexed by `boot::Cause`.  The payload is
+/// the counts as u16, with causes only ever appended.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BootCounts(pub [u16; CAUSES.len()]);
+
+/// Size of an encoded `BootCounts`.
+pub const BOOT_LEN: usize = 2 * CAUSES.len();
+
+/// CRC32 (the zlib one), bitwise, as we are short of flash, not time.
+pub const fn crc32(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    let mut i = 0;
+    while i < data.len() {
+        crc ^= data[i] as u32;
+        let mut j = 0;
+        while j < 8 {
+            crc = crc >> 1 ^ 0xedb88320 & (crc & 1).wrapping_neg();
+            j += 1;
+        }
+        i += 1;
+    }
+    !crc
+}
+
+const fn padded(len: usize) -> usize {(len + 7) & !7}
+
+/// Total size of a record with a payload of `len` bytes.
+pub const fn record_size(len: usize) -> usize {
+    HEADER_LEN + padded(len) + CRC_LEN
+}
+
+impl Header {
+    pub fn parse(bytes: &[u8]) -> Option<Header> {
+        let &[m0, m1, kind, version, l0, l1, s0, s1, ..] = bytes else {
+            return None};
+        if u16::from_le_bytes([m0, m1]) != MAGIC {
+            return None;
+        }
+        Some(Header {kind, version, len: u16::from_le_bytes([l0, l1]),
+                     seq: u16::from_le_bytes([s0, s1])})
+    }
+
+    pub fn to_bytes(self) -> [u8; HEADER_LEN] {
+        let [m0, m1] = MAGIC.to_le_bytes();
+        let [l0, l1] = self.len.to_le_bytes();
+        let [s0, s1] = self.seq.to_le_bytes();
+        [m0, m1, self.kind, self.version, l0, l1, s0, s1]
+    }
+
+    pub fn size(&self) -> usize {record_size(self.len as usize)}
+}
+
+impl<'a> Records<'a> {
+    pub fn new(page: &'a [u8]) -> Self {
+        Records {page, offset: 0, end: 0, skipped: 0}
+    }
+}
+
+impl<'a> Iterator for Records<'a> {
+    type Item = Record<'a>;
+    fn next(&mut self) -> Option<Record<'a>> {
+        loop {
+            let page = self.page;
+            let offset = self.offset;
+            let rest = &page[offset.min(page.len()) ..];
+            let header = Header::parse(rest);
+            let size = header.map_or(usize::MAX, |h| h.size());
+            let Some(header) = header.filter(|_| size <= rest.len()) else {
+                // Either erased, or garbage that we cannot skip.
+                let erased = rest.iter().take(HEADER_LEN).all(|&b| b == 0xff);
+                self.end = if erased {offset} else {page.len()};
+                self.offset = page.len();
+                return None;
+            };
+            self.offset += size;
+            let len = header.len as usize;
+            let body = &rest[.. HEADER_LEN + len];
+            let crc_at = HEADER_LEN + padded(len);
+            let crc = u32::from_le_bytes(
+                rest[crc_at .. crc_at + 4].try_into().unwrap());
+            if crc == crc32(0, body) {
+                return Some(Record {
+                    header, offset, payload: &body[HEADER_LEN ..]});
+            }
+            self.skipped += 1;
+        }
+    }
+}
+
+/// Highest sequence number in a page, None if there are no valid records.
+pub fn max_seq(page: &[u8]) -> Option<u16> {
+    Records::new(page).map(|r| r.header.seq).reduce(
+        |a, b| if newer(b, a) {b} else {a})
+}
+
+/// Sequence number comparison, allowing for wrap-around.
+const fn newer(a: u16, b: u16) -> bool {(a.wrapping_sub(b) as i16) > 0}
+
+/// The active page.
+pub fn active(store: &impl Store) -> usize {
+    let mut best = 0;
+    let mut best_seq = None;
+    for i in 0 .. PAGES {
+        if let Some(seq) = max_seq(store.page(i))
+            && best_seq.is_none_or(|b| newer(seq, b)) {
+            best = i;
+            best_seq = Some(seq);
+        }
+    }
+    best
+}
+
+/// Where the next record in a page can be written.
+pub fn free(page: &[u8]) -> usize {
+    let mut records = Records::new(page);
+    for _ in records.by_ref() {}
+    records.end
+}
+
+/// The latest valid record of a kind in a page.
+pub fn latest(page: &[u8], kind: u8) -> Option<Record<'_>> {
+    Records::new(page).filter(|r| r.header.kind == kind).last()
+}
+
+/// Append a record, compacting into the other page if needed.
+pub fn append(store: &mut impl Store, kind: u8, version: u8, payload: &[u8])
+    -> Result<(), Error>
+{
+    if record_size(payload.len()) > PAGE_SIZE || payload.len() > 0xffff {
+        return Err(Error::Full);
+    }
+    let page = active(store);
+    let seq = max_seq(store.page(page)).map_or(0, |s| s.wrapping_add(1));
+    let end = free(store.page(page));
+    let header = Header {kind, version, len: payload.len() as u16, seq};
+
+    if end + header.size() <= PAGE_SIZE {
+        return write(store, page, end, &header, payload);
+    }
+
+    // Compact.  Copy the latest record of every kind, including the one we
+    // are replacing, so that the new page is complete even if we are
+    // interrupted before writing the new record.
+    let new = (page + 1) % PAGES;
+    store.erase(new)?;
+    let mut end = 0;
+    for k in 1 ..= KINDS {
+        let Some(r) = latest(store.page(page), k) else {continue};
+        let (from, size) = (r.offset, r.header.size());
+        if end + size > PAGE_SIZE {
+            return Err(Error::Full);
+        }
+        let mut done = 0;
+        while done < size {
+            let mut chunk = [0; 64];
+            let n = (size - done).min(chunk.len());
+            let at = from + done;
+            chunk[.. n].copy_from_slice(&store.page(page)[at .. at + n]);
+            store.program(new, end + done, &chunk[.. n])?;
+            done += n;
+        }
+        end += size;
+    }
+    if end + header.size() > PAGE_SIZE {
+        return Err(Error::Full);
+    }
+    write(store, new, end, &header, payload)?;
+    store.erase(page)
+}
+
+fn write(store: &mut impl Store, page: usize, offset: usize, header: &Header,
+         payload: &[u8]) -> Result<(), Error> {
+    let bytes = header.to_bytes();
+    let crc = crc32(crc32(0, &bytes), payload);
+    store.program(page, offset, &bytes)?;
+    store.program(page, offset + HEADER_LEN, payload)?;
+    store.program(page, offset + HEADER_LEN + padded(payload.len()),
+                  &crc.to_le_bytes())
+}
+
+/// A little endian field of a record.
+trait Field {
+    fn put(&self, buf: &mut [u8], len: &mut usize);
+    fn get(&mut self, data: &mut &[u8]);
+}
+
+macro_rules! field {($($t:ty)*) => {$(
+    impl Field for $t {
+        fn put(&self, buf: &mut [u8], len: &mut usize) {
+            let bytes = self.to_le_bytes();
+            buf[*len .. *len + bytes.len()].copy_from_slice(&bytes);
+            *len += bytes.len();
+        }
+        fn get(&mut self, data: &mut &[u8]) {
+            if let Some((bytes, rest)) = data.split_first_chunk() {
+                *self = <$t>::from_le_bytes(*bytes);
+                *data = rest;
+            }
+            else {
+                *data = &[];
+            }
+        }
+    }
+)*}}
+
+field!(u8 u16 u32 i32);
+
+impl Field for LedCorr {
+    fn put(&self, buf: &mut [u8], len: &mut usize) {
+        buf[*len .. *len + self.0.len()].copy_from_slice(&self.0);
+        *len += self.0.len();
+    }
+    fn get(&mut self, data: &mut &[u8]) {
+        if let Some((bytes, rest)) = data.split_first_chunk() {
+            self.0 = *bytes;
+            *data = rest;
+        }
+        else {
+            *data = &[];
+        }
+    }
+}
+
+/// Call `$f(name, &mut field)` for each config field, in record order.
+/// Fields are only ever appended.
+macro_rules! config_fields {($s:expr, $f:ident) => {{
+    let s = $s;
+    $f("vf_mv", &mut s.vf_mv);
+    $f("pwm_scale", &mut s.pwm_scale);
+    $f("led_ohms", &mut s.led_ohms);
+    $f("source_ohms", &mut s.source_ohms);
+    $f("current_cap", &mut s.current_cap);
+    $f("lsi_trim", &mut s.lsi_trim);
+    $f("on_start", &mut s.on_start);
+    $f("on_minutes", &mut s.on_minutes);
+    $f("season", &mut s.season);
+    $f("low_mv", &mut s.low_mv);
+    $f("critical_mv", &mut s.critical_mv);
+    $f("temp_comp", &mut s.temp_comp);
+    $f("vref_cal", &mut s.vref_cal);    // Version 2.
+    $f("led_corr", &mut s.led_corr);    // Version 3.
+}}}
+
+impl Config {
+    /// Sanity check, so that a bad record cannot stop us working.
+    pub const fn is_valid(&self) -> bool {
+        self.led_ohms != 0 && self.vf_mv < 5000 && self.on_start < 24 * 60
+            && self.on_minutes <= 24 * 60 && self.critical_mv <= self.low_mv
+            && (self.vref_cal == 0
+                || 1400 <= self.vref_cal && self.vref_cal <= 1900)
+    }
+
+    /// Encode into `buf`, returning the length.
+    pub fn encode(&self, buf: &mut [u8; CONFIG_LEN]) -> usize {
+        let mut len = 0;
+        let mut copy = *self;
+        let mut put = |_, f: &mut dyn Field| f.put(buf, &mut len);
+        config_fields!(&mut copy, put);
+        len
+    }
+
+    /// Decode a record payload over the current values.
+    pub fn decode(&mut self, payload: &[u8]) {
+        let mut data = payload;
+        let mut get = |_, f: &mut dyn Field| f.get(&mut data);
+        config_fields!(self, get);
+    }
+}
+
+impl BootCounts {
+    pub fn encode(&self) -> [u8; BOOT_LEN] {
+        let mut buf = [0; BOOT_LEN];
+        let mut len = 0;
+        for count in &self.0 {
+            count.put(&mut buf, &mut len);
+        }
+        buf
+    }
+
+    /// Decode a record payload, missing counts are zero.
+    pub fn decode(payload: &[u8]) -> BootCounts {
+        let mut data = payload;
+        let mut counts = BootCounts([0; _]);
+        for count in &mut counts.0 {
+            count.get(&mut data);
+        }
+        counts
+    }
+}
+
+impl<'a> Iterator for Messages<'a> {
+    type Item = Message<'a>;
+    fn next(&mut self) -> Option<Message<'a>> {
+        let &[flags, weight, len, ref rest @ ..] = self.0 else {return None};
+        let Some((text, rest)) = rest.split_at_checked(len as usize) else {
+            self.0 = &[];
+            return None;
+        };
+        self.0 = rest;
+        Some(Message {main: flags & MESSAGE_MAIN != 0, weight, text})
+    }
+}
+
+impl Message<'_> {
+    /// Append the encoded message to `buf[.. *len]`, returning false if it
+    /// does not fit.
+    pub fn encode(&self, buf: &mut [u8], len: &mut usize) -> bool {
+        let size = 3 + self.text.len();
+        if self.text.len() > 255 || *len + size > buf.len() {
+            return false;
+        }
+        let flags = if self.main {MESSAGE_MAIN} else {0};
+        buf[*len .. *len + 3].copy_from_slice(
+            &[flags, self.weight, self.text.len() as u8]);
+        buf[*len + 3 .. *len + size].copy_from_slice(self.text);
+        *len += size;
+        true
+    }
+}
+
+#[cfg(test)]
+pub struct TestStore(pub [[u8; PAGE_SIZE]; PAGES]);
+
+#[cfg(test)]
+impl Store for TestStore {
+    fn page(&self, page: usize) -> &[u8] {&self.0[page]}
+    fn erase(&mut self, page: usize) -> Result<(), Error> {
+        self.0[page].fill(0xff);
+        Ok(())
+    }
+    fn program(&mut self, page: usize, offset: usize, data: &[u8])
+        -> Result<(), Error> {
+        assert_eq!(offset % 8, 0);
+        for (i, &b) in data.iter().enumerate() {
+            // Double-words may only be programmed once.
+            assert_eq!(self.0[page][offset + i], 0xff);
+            self.0[page][offset + i] = b;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn crc() {
+    assert_eq!(crc32(0, b"123456789"), 0xcbf43926);
+    assert_eq!(crc32(crc32(0, b"1234"), b"56789"), 0xcbf43926);
+}
+
+#[test]
+fn config_round_trip() {
+    let config = Config {
+        vf_mv: 2750, pwm_scale: 206250, led_ohms: 330, source_ohms: 10,
+        current_cap: 2000, lsi_trim: -1234, on_start: 1020, on_minutes: 360,
+        season: 2, low_mv: 2400, critical_mv: 2100, temp_comp: 655,
+        vref_cal: 1650, led_corr: LedCorr(core::array::from_fn(|i| i as u8))};
+    let mut buf = [0; CONFIG_LEN];
+    let len = config.encode(&mut buf);
+    let mut decoded = Config::default();
+    decoded.decode(&buf[.. len]);
+    assert_eq!(decoded, config);
+
+    // A short (older) record leaves the later fields alone.
+    let mut decoded = Config {temp_comp: 99, ..Config::default()};
+    decoded.decode(&buf[.. 6]);
+    assert_eq!(decoded.pwm_scale, 206250);
+    assert_eq!(decoded.led_ohms, 0);
+    assert_eq!(decoded.temp_comp, 99);
+    assert_eq!(decoded.led_corr, LedCorr::default());
+}
+
+#[test]
+fn messages() {
+    let list = [
+        Message {main: true, weight: 1, text: b"HELLO"},
+        Message {main: false, weight: 10, text: b"PEACE"},
+        Message {main: false, weight: 5, text: b""},
+    ];
+    let mut buf = [0; 20];
+    let mut len = 0;
+    for m in &list {
+        assert!(m.encode(&mut buf, &mut len));
+    }
+    assert_eq!(len, 19);
+    assert!(Messages(&buf[.. len]).eq(list));
+    assert!(!list[0].encode(&mut buf, &mut len));
+
+    // Truncated.
+    assert!(Messages(&buf[.. len - 1]).eq(list[.. 2].iter().copied()));
+}
+
+#[test]
+fn boot_counts() {
+    let counts = BootCounts(core::array::from_fn(|i| i as u16 * 1000));
+    let buf = counts.encode();
+    assert_eq!(BootCounts::decode(&buf), counts);
+    let mut short = counts;
+    short.0[CAUSES.len() - 1] = 0;
+    assert_eq!(BootCounts::decode(&buf[.. BOOT_LEN - 2]), short);
+}
+
+#[test]
+fn log() {
+    let mut store = TestStore([[0xff; PAGE_SIZE]; PAGES]);
+    assert!(latest(store.page(0), KIND_CONFIG).is_none());
+
+    // Fill up the first page, and then some.
+    for i in 0 .. 200u32 {
+        append(&mut store, KIND_CONFIG, 1, &i.to_le_bytes()).unwrap();
+        let page = active(&store);
+        let r = latest(store.page(page), KIND_CONFIG).unwrap();
+        assert_eq!(r.payload, i.to_le_bytes());
+        assert_eq!(r.header.seq, i as u16);
+    }
+    // 24 byte records, 85 per page, and each compaction copies one.
+    assert_eq!(active(&store), 0);
+    assert_eq!(Records::new(store.page(0)).count(), 200 - 169 + 1);
+    assert_eq!(max_seq(store.page(1)), None);
+
+    // A torn write is ignored, and skipped over.
+    let page = active(&store);
+    let end = free(store.page(page));
+    store.program(page, end, &Header {
+        kind: KIND_CONFIG, version: 1, len: 4, seq: 1000}.to_bytes()).unwrap();
+    store.program(page, end + 8, b"torn").unwrap();
+    assert_eq!(latest(store.page(page), KIND_CONFIG).unwrap().payload,
+               199u32.to_le_bytes());
+    append(&mut store, KIND_CONFIG, 1, b"next").unwrap();
+    assert_eq!(latest(store.page(page), KIND_CONFIG).unwrap().payload, b"next");
+
+    // Garbage forces a compaction.
+    let end = free(store.page(page));
+    store.program(page, end, b"garbage!").unwrap();
+    append(&mut store, KIND_CONFIG, 1, b"more").unwrap();
+    assert_eq!(active(&store), 1 - page);
+    assert_eq!(latest(store.page(1 - page), KIND_CONFIG).unwrap().payload,
+               b"more");
+
+    // Too big.
+    assert_eq!(append(&mut store, KIND_CONFIG, 1, &[0; PAGE_SIZE]),
+               Err(Error::Full));
+}
@@ -0,0 +1,329 @@
+//! Real time clock.  The RTC is clocked from the LSI, which is only accurate to
+//! a few percent, so we carry a per-board trim (in ppm) that sets the
+//! prescalers and the smooth calibration.
+
+use stm32g030::Interrupt::RTC_TAMP as INTERRUPT;
+
+use crate::console::Args;
+
+/// Nominal LSI frequency.
+const LSI_HZ: u32 = 32_000;
+
+/// Asynchronous prescaler.  This is kept small so that the synchronous
+/// prescaler has fine enough resolution (500ppm) for the smooth calibration
+/// (±487ppm) to cover the remainder.
+const PREDIV_A: u32 = 16;
+
+/// Longest wake-up timer period, in seconds.
+pub const MAX_WAKEUP: u32 = 65536;
+
+/// Unlock keys for RTC_WPR.
+const WPR_KEY1: u32 = 0xca;
+const WPR_KEY2: u32 = 0x53;
+
+/// Calendar date and time.  The year is the full year, 2001 ..= 2099.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DateTime {
+    pub year  : u16,
+    pub month : u8,
+    pub day   : u8,
+    pub hour  : u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+pub fn init() {
+    let pwr = unsafe {&*stm32g030::PWR::ptr()};
+    let rcc = unsafe {&*stm32g030::RCC::ptr()};
+
+    rcc.APBENR1.modify(|_, w| w.PWREN().set_bit().RTCAPBEN().set_bit());
+
+    // The LSI is turned off by a system reset, but the RTC (in the backup
+    // domain) keeps its setup.
+    rcc.CSR.modify(|_, w| w.LSION().set_bit());
+    while !rcc.CSR.read().LSIRDY().bit() {
+    }
+
+    pwr.CR1.modify(|_, w| w.DBP().set_bit());
+    let bdcr = rcc.BDCR.read();
+    if !bdcr.RTCEN().bit() || bdcr.RTCSEL().bits() != 2 {
+        // Not set-up, reset the backup domain and select LSI.
+        rcc.BDCR.write(|w| w.BDRST().set_bit());
+        rcc.BDCR.write(|w| w.RTCSEL().bits(2).RTCEN().set_bit());
+    }
+
+    apply_trim();
+}
+
+/// Program the prescalers and calibration from the current config trim.
+/// Entering INIT stops the calendar, so the prescalers are only written if
+/// they change, and a reset doesn't lose time.
+pub fn apply_trim() {
+    let rtc = unsafe {&*stm32g030::RTC::ptr()};
+    let (prediv_s, calr) = prescale(crate::config::get().lsi_trim);
+    let prer = (PREDIV_A - 1) << 16 | prediv_s;
+    let prer_ok = rtc.PRER.read().bits() == prer;
+    let calr_ok = rtc.CALR.read().bits() == calr;
+    if prer_ok && calr_ok {
+        return;
+    }
+
+    unlock();
+    if !prer_ok {
+        enter_init();
+        rtc.PRER.write(|w| w.PREDIV_S().bits(prediv_s as u16));
+        rtc.PRER.modify(|_, w| w.PREDIV_A().bits(PREDIV_A as u8 - 1));
+        exit_init();
+    }
+    if !calr_ok {
+        while rtc.ICSR.read().RECALPF().bit() {
+        }
+        rtc.CALR.write(|w| w.bits(calr));
+    }
+    lock();
+}
+
+/// Has the calendar been set?
+pub fn is_set() -> bool {
+    let rtc = unsafe {&*stm32g030::RTC::ptr()};
+    rtc.ICSR.read().INITS().bit()
+}
+
+pub fn read() -> DateTime {
+    let rtc = unsafe {&*stm32g030::RTC::ptr()};
+    // Reading TR locks the shadow DR until DR is read.
+    let tr = rtc.TR.read().bits();
+    let dr = rtc.DR.read().bits();
+    DateTime::from_bcd(tr, dr)
+}
+
+pub fn set(dt: &DateTime) {
+    let rtc = unsafe {&*stm32g030::RTC::ptr()};
+    let (tr, dr) = dt.to_bcd();
+    unlock();
+    enter_init();
+    rtc.TR.write(|w| w.bits(tr));
+    rtc.DR.write(|w| w.bits(dr));
+    exit_init();
+    lock();
+}
+
+/// Arm the wake-up timer to interrupt after `seconds`.
+pub fn wakeup_after(seconds: u32) {
+    let rtc = unsafe {&*stm32g030::RTC::ptr()};
+    unlock();
+    rtc.CR.modify(|_, w| w.WUTE().clear_bit());
+    while !rtc.ICSR.read().WUTWF().bit() {
+    }
+    rtc.WUTR.write(|w| w.WUT().bits((seconds.clamp(1, MAX_WAKEUP) - 1) as u16));
+    rtc.SCR.write(|w| w.CWUTF().set_bit());
+    // Clock the wake-up timer from the 1Hz ck_spre.
+    rtc.CR.modify(
+        |_, w| w.WUCKSEL().bits(4).WUTIE().set_bit().WUTE().set_bit());
+    lock();
+    stm_common::interrupt::enable_priority(INTERRUPT, crate::cpu::PRIO_PENDSV);
+}
+
+/// Disable the wake-up timer.
+pub fn wakeup_disable() {
+    let rtc = unsafe {&*stm32g030::RTC::ptr()};
+    unlock();
+    rtc.CR.modify(|_, w| w.WUTE().clear_bit().WUTIE().clear_bit());
+    lock();
+    rtc.SCR.write(|w| w.CWUTF().set_bit());
+}
+
+fn isr() {
+    // One shot, disable the timer.  Wake-up is via EXTI line 19, which is
+    // a direct line, so there is nothing to clear there.
+    wakeup_disable();
+}
+
+fn unlock() {
+    let rtc = unsafe {&*stm32g030::RTC::ptr()};
+    rtc.WPR.write(|w| w.bits(WPR_KEY1));
+    rtc.WPR.write(|w| w.bits(WPR_KEY2));
+}
+
+fn lock() {
+    let rtc = unsafe {&*stm32g030::RTC::ptr()};
+    rtc.WPR.write(|w| w.bits(0xff));
+}
+
+fn enter_init() {
+    let rtc = unsafe {&*stm32g030::RTC::ptr()};
+    rtc.ICSR.modify(|_, w| w.INIT().set_bit());
+    while !rtc.ICSR.read().INITF().bit() {
+    }
+}
+
+fn exit_init() {
+    let rtc = unsafe {&*stm32g030::RTC::ptr()};
+    rtc.ICSR.modify(|_, w| w.INIT().clear_bit());
+}
+
+/// Compute the synchronous prescaler (PRER.PREDIV_S) and the smooth
+/// calibration (CALR) for an LSI running `ppm` parts-per-million fast.
+pub const fn prescale(ppm: i32) -> (u32, u32) {
+    // LSI frequency in µHz.
+    let lsi = LSI_HZ as i64 * (1_000_000 + ppm as i64);
+    let unit = PREDIV_A as i64 * 1_000_000;
+    let prediv_s = (lsi + unit / 2) / unit;
+    // The calibration scales the LSI by (1 + cal / 2²⁰).  We want
+    // lsi × (1 + cal / 2²⁰) == unit × prediv_s.
+    let target = (unit * prediv_s) << 20;
+    let cal = (target + lsi / 2) / lsi - (1 << 20);
+    assert!(cal > -512 && cal <= 512);
+    let calr = if cal > 0 {1 << 15 | (512 - cal) as u32} else {-cal as u32};
+    (prediv_s as u32 - 1, calr)
+}
+
+/// Day number relative to 2000-01-01, valid for the Gregorian calendar.
+pub const fn days(year: u16, month: u8, day: u8) -> i32 {
+    // Shift the year to start in March, so that the leap day is last.
+    let (y, m) = if month <= 2 {(year as i32 - 1, month as i32 + 9)}
+                 else {(year as i32, month as i32 - 3)};
+    let leaps = y / 4 - y / 100 + y / 400;
+    365 * y + leaps + (153 * m + 2) / 5 + day as i32 - 730426
+}
+
+pub const fn days_in_month(year: u16, month: u8) -> u8 {
+    match month {
+        2 => if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) {29}
+             else {28},
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+const fn bcd(x: u8) -> u32 {(x / 10 * 16 + x % 10) as u32}
+
+const fn unbcd(x: u32) -> u8 {(x >> 4 & 15) as u8 * 10 + (x & 15) as u8}
+
+impl DateTime {
+    pub const fn is_valid(&self) -> bool {
+        self.year > 2000 && self.year < 2100
+            && self.month >= 1 && self.month <= 12
+            && self.day >= 1 && self.day <= days_in_month(self.year, self.month)
+            && self.hour < 24 && self.minute < 60 && self.second < 60
+    }
+
+    /// Days since 2000-01-01.
+    pub const fn days(&self) -> i32 {days(self.year, self.month, self.day)}
+
+    /// Day of week, Monday is 1 and Sunday is 7, as the RTC counts them.
+    pub const fn weekday(&self) -> u8 {
+        // 2000-01-01 was a Saturday.
+        ((self.days() + 5) % 7 + 1) as u8
+    }
+
+    const fn from_bcd(tr: u32, dr: u32) -> DateTime {
+        DateTime {
+            year  : 2000 + unbcd(dr >> 16 & 0xff) as u16,
+            month : unbcd(dr >> 8 & 0x1f),
+            day   : unbcd(dr & 0x3f),
+            hour  : unbcd(tr >> 16 & 0x3f),
+            minute: unbcd(tr >> 8 & 0x7f),
+            second: unbcd(tr & 0x7f),
+        }
+    }
+
+    const fn to_bcd(&self) -> (u32, u32) {
+        let tr = bcd(self.hour) << 16 | bcd(self.minute) << 8
+            | bcd(self.second);
+        let dr = bcd((self.year - 2000) as u8) << 16
+            | (self.weekday() as u32) << 13
+            | bcd(self.month) << 8 | bcd(self.day);
+        (tr, dr)
+    }
+}
+
+impl core::fmt::Display for DateTime {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "{:04}-{:02}-{:02} {:02}:{:02}:{:02}", self.year, self.month,
+               self.day, self.hour, self.minute, self.second)
+    }
+}
+
+impl crate::cpu::Config {
+    pub const fn rtc(&mut self) -> &mut Self {
+        self.isr(INTERRUPT, isr)
+    }
+}
+
+/// Console: `date [YYYY-MM-DD HH:MM:SS]`.
+pub fn cmd_date(args: &mut Args) {
+    if !args.done() {
+        let mut n = || args.number().unwrap_or(-1);
+        let dt = DateTime {
+            year: n() as u16, month: n() as u8, day: n() as u8,
+            hour: n() as u8, minute: n() as u8, second: n() as u8};
+        if !dt.is_valid() || !args.done() {
+            stm_common::dbgln!("date YYYY-MM-DD HH:MM:SS");
+            return;
+        }
+        set(&dt);
+    }
+    stm_common::dbgln!("{}", read());
+}
+
+/// Console: `trim [ppm]`, the LSI error, positive if it runs fast.
+pub fn cmd_trim(args: &mut Args) {
+    if let Some(ppm) = args.number() {
+        if ppm.abs() > 50_000 || !args.done() {
+            stm_common::dbgln!("trim ±50000");
+            return;
+        }
+        unsafe {crate::config::CONFIG.as_mut()}.lsi_trim = ppm;
+        apply_trim();
+    }
+    stm_common::dbgln!("trim {}", crate::config::get().lsi_trim);
+}
+
+#[test]
+fn check_isr() {
+    assert!(crate::cpu::VECTORS.isr[INTERRUPT as usize] == isr);
+}
+
+#[test]
+fn prescale_accuracy() {
+    for ppm in (-50_000 ..= 50_000).step_by(97) {
+        let (prediv_s, calr) = prescale(ppm);
+        let cal = if calr & 1 << 15 != 0 {512 - (calr & 511) as i64}
+                  else {-(calr as i64)};
+        let lsi = LSI_HZ as f64 * (1.0 + ppm as f64 * 1e-6);
+        let hz = lsi * (1.0 + cal as f64 / 1048576.0)
+            / (PREDIV_A as f64 * (prediv_s + 1) as f64);
+        assert!((hz - 1.0).abs() < 2e-6, "{ppm} {prediv_s} {calr:#x} {hz}");
+    }
+    assert_eq!(prescale(0), (1999, 0));
+}
+
+#[test]
+fn day_numbers() {
+    assert_eq!(days(2000, 1, 1), 0);
+    assert_eq!(days(2000, 3, 1), 60);
+    assert_eq!(days(2001, 1, 1), 366);
+    assert_eq!(days(2026, 10, 19) - days(2025, 12, 31), 292);
+    let mut n = days(2001, 1, 1);
+    for year in 2001 .. 2100 {
+        for month in 1 ..= 12 {
+            for day in 1 ..= days_in_month(year, month) {
+                assert_eq!(days(year, month, day), n);
+                n += 1;
+            }
+        }
+    }
+}
+
+#[test]
+fn bcd_round_trip() {
+    let dt = DateTime {
+        year: 2026, month: 10, day: 19, hour: 23, minute: 59, second: 7};
+    assert!(dt.is_valid());
+    assert_eq!(dt.weekday(), 1);
+    let (tr, dr) = dt.to_bcd();
+    assert_eq!(tr, 0x235907);
+    assert_eq!(dr, 0x26_1019 | 1 << 13);
+    assert_eq!(DateTime::from_bcd(tr, dr), dt);
+}
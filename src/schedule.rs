@@ -0,0 +1,102 @@
+//! Daily on/off schedule, like the timer on battery fairy lights.  The display
+//! is on for `on_minutes` starting at `on_start` each day, and we sleep in STOP
+//! mode in between.
+//!
+//! If the RTC has not been set, then we start it at the beginning of the on
+//! period, so that the schedule runs relative to power-up.  Warm resets keep
+//! the time.
+
+use crate::console::Args;
+
+const DAY: u32 = 24 * 3600;
+
+/// Flag in TAMP_BKP0R that `init` has started the clock.  The backup
+/// registers survive a reset, and are cleared with the backup domain.
+const STARTED: u32 = 0x5c4e_d01e;
+
+/// Start the clock at the beginning of the on period, if it has not been set.
+/// Only once, so that a watchdog or crash reset carries on with the schedule.
+pub fn init() {
+    let tamp = unsafe {&*stm32g030::TAMP::ptr()};
+    if !crate::rtc::is_set() && tamp.BKP0R.read().bits() != STARTED {
+        let start = crate::config::get().on_start;
+        // Leave the year as 2000, so that the RTC still reads as not set.
+        crate::rtc::set(&crate::rtc::DateTime {
+            year: 2000, month: 1, day: 1,
+            hour: (start / 60) as u8, minute: (start % 60) as u8, second: 0});
+        tamp.BKP0R.write(|w| w.bits(STARTED));
+    }
+}
+
+/// If the schedule says we are off, then sleep until it is time to be on.
+pub fn check() {
+    let mut stopped = false;
+    loop {
+        let config = crate::config::get();
+        let now = crate::rtc::read();
+        let second = (now.hour as u32 * 60 + now.minute as u32) * 60
+            + now.second as u32;
+        let off = off_for(second, config.on_start, config.on_minutes);
+        if off == 0 {
+            if stopped {
+                crate::pulse::start();
+            }
+            return;
+        }
+        crate::power::stop(off.min(crate::rtc::MAX_WAKEUP));
+        stopped = true;
+    }
+}
+
+/// Return the number of seconds until the on period starts, or zero if we are
+/// in the on period.  `second` is the time of day in seconds, `start` the
+/// time of day in minutes that the on period starts, and `minutes` the length
+/// of the on period.  An on period of zero (or a day or more) is always on.
+pub const fn off_for(second: u32, start: u16, minutes: u16) -> u32 {
+    if minutes == 0 || minutes as u32 * 60 >= DAY {
+        return 0;
+    }
+    let since_start = (second + DAY - start as u32 * 60) % DAY;
+    if since_start < minutes as u32 * 60 {0} else {DAY - since_start}
+}
+
+/// Console: `sched [HH:MM minutes]`.
+pub fn cmd_sched(args: &mut Args) {
+    if !args.done() {
+        let hour = args.number().unwrap_or(-1);
+        let minute = args.number().unwrap_or(-1);
+        let minutes = args.number().unwrap_or(-1);
+        if !(0 .. 24).contains(&hour) || !(0 .. 60).contains(&minute)
+            || !(0 ..= 1440).contains(&minutes) || !args.done() {
+            stm_common::dbgln!("sched HH:MM minutes");
+            return;
+        }
+        let config = unsafe {crate::config::CONFIG.as_mut()};
+        config.on_start = (hour * 60 + minute) as u16;
+        config.on_minutes = minutes as u16;
+    }
+    let config = crate::config::get();
+    stm_common::dbgln!("sched {:02}:{:02} {}", config.on_start / 60,
+                       config.on_start % 60, config.on_minutes);
+}
+
+#[test]
+fn schedule() {
+    const H: u32 = 3600;
+    // On 17:00 for 6 hours.
+    let off = |second| off_for(second, 17 * 60, 6 * 60);
+    assert_eq!(off(17 * H), 0);
+    assert_eq!(off(17 * H - 1), 1);
+    assert_eq!(off(23 * H - 1), 0);
+    assert_eq!(off(23 * H), 18 * H);
+    assert_eq!(off(0), 17 * H);
+    // On 22:00 for 6 hours, wrapping midnight.
+    let off = |second| off_for(second, 22 * 60, 6 * 60);
+    assert_eq!(off(23 * H), 0);
+    assert_eq!(off(3 * H), 0);
+    assert_eq!(off(4 * H), 18 * H);
+    assert_eq!(off(21 * H + 1), H - 1);
+    // Always on.
+    assert_eq!(off_for(12345, 100, 0), 0);
+    assert_eq!(off_for(12345, 100, 1440), 0);
+}
@@ -0,0 +1,225 @@
+//! Seasonal message sets.  The set is chosen by date once the clock is set,
+//! or forced via `config::Config::season`.
+
+use stm_common::vcell::UCell;
+
+use crate::chars::map_str;
+use crate::console::Args;
+use crate::marque::{marque_string, rmarque_string};
+use crate::pendsv::FIFTH;
+use crate::random::{RANDOM, Random};
+use crate::select::{MAX, Mode, Selector};
+
+pub struct Season {
+    pub name: &'static str,
+    /// First and last (month, day), inclusive, possibly wrapping the year end.
+    /// None for sets that are only chosen by config.
+    pub dates: Option<((u8, u8), (u8, u8))>,
+    /// Main message, already mapped.
+    pub main: &'static [u8],
+    /// Exception shows, with their weights, the number of times each plays
+    /// per cycle.
+    pub shows: &'static [(fn(), u8)],
+    /// Exception shows that need the time to be set.
+    pub clock_shows: &'static [(fn(), u8)],
+}
+
+pub const CHRISTMAS: usize = 0;
+pub const GENERIC: usize = 4;
+
+pub static SEASONS: [Season; 5] = [
+    Season {
+        name: "christmas", dates: Some(((12, 1), (12, 26))),
+        main: &map_str(b"MERRY XMAS "),
+        shows: &[(crate::nice1, 2), (crate::nice2, 2), (crate::nice3, 2),
+                 (crate::nice4, 2), (crate::nice5, 2), (crate::naughty1, 1),
+                 (crate::naughty2, 1), (crate::temp::show_temperature, 1)],
+        clock_shows: &[(crate::clock::show_time, 2),
+                       (crate::clock::show_countdown, 3),
+                       (crate::clock::show_binary, 1)],
+    },
+    Season {
+        name: "new year", dates: Some(((12, 27), (1, 6))),
+        main: &map_str(b"HAPPY NEW YEAR "),
+        shows: &[(crate::nice1, 1), (crate::nice5, 1), (cheers, 3),
+                 (crate::naughty1, 1)],
+        clock_shows: &[(crate::clock::show_time, 2),
+                       (crate::clock::show_binary, 1)],
+    },
+    Season {
+        name: "halloween", dates: Some(((10, 17), (10, 31))),
+        main: &map_str(b"HAPPY HALLOWEEN "),
+        shows: &[(boo, 2), (trick_or_treat, 2), (crate::naughty1, 1)],
+        clock_shows: &[(crate::clock::show_time, 1),
+                       (crate::clock::show_binary, 1)],
+    },
+    Season {
+        name: "birthday", dates: None,
+        main: &map_str(b"HAPPY BIRTHDAY "),
+        shows: &[(crate::nice1, 1), (crate::nice2, 1), (crate::nice3, 1),
+                 (crate::nice4, 1)],
+        clock_shows: &[(crate::clock::show_time, 1)],
+    },
+    Season {
+        name: "generic", dates: None,
+        main: &map_str(b"HELLO "),
+        shows: &[(crate::nice1, 2), (crate::nice2, 2), (crate::nice3, 2),
+                 (crate::nice4, 2), (crate::nice5, 2), (crate::naughty1, 1),
+                 (crate::naughty2, 1), (crate::temp::show_temperature, 2)],
+        clock_shows: &[(crate::clock::show_time, 2),
+                       (crate::clock::show_binary, 1)],
+    },
+];
+
+/// The exception show bag, and what it is for.  It starts afresh when the
+/// season or the clock shows change, so that no bag is carried over to a
+/// different list of shows.
+struct Shows {
+    selector: Selector,
+    /// The season index plus one, or zero for none yet.
+    season: u8,
+    clock: bool,
+}
+
+static SHOWS: UCell<Shows> = UCell::new(Shows::NEW);
+
+impl Shows {
+    const NEW: Shows = Shows {selector: Selector::NEW, season: 0, clock: false};
+
+    /// Pick from the shows of `season`, with the clock shows or not.
+    fn choose(&mut self, season: usize, clock: bool, weights: &[u8],
+              random: &Random) -> Option<usize> {
+        let key = season as u8 + 1;
+        if key != self.season || clock != self.clock {
+            *self = Shows {selector: Selector::NEW, season: key, clock};
+        }
+        self.selector.choose(Mode::Bag, weights, random)
+    }
+}
+
+/// The currently active set.
+pub fn active() -> &'static Season {
+    let date = if crate::rtc::is_set() {
+        let now = crate::rtc::read();
+        Some((now.month, now.day))
+    }
+    else {
+        None
+    };
+    &SEASONS[select(crate::config::get().season, date)]
+}
+
+/// Choose a set.  A non-zero `forced` is a (1-based) index into SEASONS.
+/// Otherwise, go by the date if we have one, else Christmas.
+pub fn select(forced: u8, date: Option<(u8, u8)>) -> usize {
+    if forced != 0 && forced as usize <= SEASONS.len() {
+        return forced as usize - 1;
+    }
+    let Some(today) = date else {return CHRISTMAS};
+    SEASONS.iter().position(|s| match s.dates {
+        Some((from, to)) => in_range(today, from, to),
+        None => false,
+    }).unwrap_or(GENERIC)
+}
+
+fn in_range(date: (u8, u8), from: (u8, u8), to: (u8, u8)) -> bool {
+    let key = |(month, day): (u8, u8)| month as u32 * 32 + day as u32;
+    let (date, from, to) = (key(date), key(from), key(to));
+    if from <= to {from <= date && date <= to} else {date >= from || date <= to}
+}
+
+impl Season {
+    pub fn show_main(&self) {
+        marque_string(&mut 0, self.main, FIFTH);
+    }
+
+    /// Run a random exception show, each its weight times per cycle, and
+    /// never twice in a row.
+    pub fn show_exception(&self) {
+        let clock = crate::rtc::is_set();
+        let clock_shows: &[(fn(), u8)] =
+            if clock {self.clock_shows} else {&[]};
+        let all = || self.shows.iter().chain(clock_shows);
+        let mut weights = [0; MAX];
+        for (w, &(_, weight)) in weights.iter_mut().zip(all()) {
+            *w = weight;
+        }
+        let n = all().count().min(MAX);
+        let season = SEASONS.iter().position(|s| core::ptr::eq(s, self));
+        let shows = unsafe {SHOWS.as_mut()};
+        let Some(i) = shows.choose(season.unwrap_or(0), clock,
+                                   &weights[.. n], &RANDOM) else {return};
+        let Some((show, _)) = all().nth(i) else {return};
+        show();
+    }
+}
+
+fn cheers() {
+    const STR: &[u8] = &map_str(b"CHEERS ");
+    rmarque_string(STR, FIFTH);
+}
+
+fn boo() {
+    const STR: &[u8] = &map_str(b"BOO ");
+    rmarque_string(STR, FIFTH);
+}
+
+fn trick_or_treat() {
+    const STR: &[u8] = &map_str(b"TRICK OR TREAT ");
+    rmarque_string(STR, FIFTH);
+}
+
+/// Console: `season [n]`, zero to go by date.
+pub fn cmd_season(args: &mut Args) {
+    if let Some(n) = args.number() {
+        if !(0 ..= SEASONS.len() as i32).contains(&n) || !args.done() {
+            stm_common::dbgln!("season 0..={}", SEASONS.len());
+            return;
+        }
+        unsafe {crate::config::CONFIG.as_mut()}.season = n as u8;
+    }
+    stm_common::dbgln!("season {} {}", crate::config::get().season,
+                       active().name);
+}
+
+#[test]
+fn select_by_date() {
+    let by_date = |month, day| SEASONS[select(0, Some((month, day)))].name;
+    assert_eq!(by_date(12, 1), "christmas");
+    assert_eq!(by_date(12, 26), "christmas");
+    assert_eq!(by_date(12, 27), "new year");
+    assert_eq!(by_date(1, 1), "new year");
+    assert_eq!(by_date(1, 6), "new year");
+    assert_eq!(by_date(1, 7), "generic");
+    assert_eq!(by_date(10, 16), "generic");
+    assert_eq!(by_date(10, 31), "halloween");
+    assert_eq!(by_date(11, 1), "generic");
+    assert_eq!(select(0, None), CHRISTMAS);
+    assert_eq!(SEASONS[select(4, Some((12, 25)))].name, "birthday");
+    assert_eq!(select(99, None), CHRISTMAS);
+}
+
+#[test]
+fn season_change() {
+    fn draw(shows: &mut Shows, season: usize, random: &Random, count: usize)
+            -> Vec<usize> {
+        (0 .. count).map(|_| shows.choose(season, false, &[1; 4], random))
+            .map(Option::unwrap).collect()
+    }
+    // Within a season, the bag carries on, so a cycle plays every show.
+    let mut shows = Shows::NEW;
+    let mut cycle = draw(&mut shows, 0, &Random::new(3), 4);
+    cycle.sort();
+    assert_eq!(cycle, [0, 1, 2, 3]);
+    // Part way through a bag, a new season with as many shows starts afresh.
+    draw(&mut shows, 0, &Random::new(3), 3);
+    let mut fresh = Shows::NEW;
+    assert_eq!(draw(&mut shows, 1, &Random::new(8), 8),
+               draw(&mut fresh, 1, &Random::new(8), 8));
+    // As does the clock being set.
+    draw(&mut shows, 1, &Random::new(3), 3);
+    let mut fresh = Shows::NEW;
+    let clock = |shows: &mut Shows| shows.choose(1, true, &[1; 4],
+                                                 &Random::new(8));
+    assert_eq!(clock(&mut shows), clock(&mut fresh));
+}
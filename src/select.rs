@@ -0,0 +1,125 @@
+//! Choosing shows and messages.
+//!
+//! A `Selector` picks an index into a list of weights, never the same one
+//! twice in a row, unless it is the only one.  In `Mode::Weighted`, each pick
+//! is by weight.  In `Mode::Bag`, each item goes in a bag `weight` times, and
+//! picks are drawn from the bag until it is empty, so every item plays in
+//! each cycle.  If only the last item is left in the bag, the next cycle is
+//! added, so that it doesn't repeat.
+//!
+//! The mode is given on each pick, so that a selector is all zeros, and can
+//! live in the BSS.
+
+use crate::random::Random;
+
+/// Most items a selector handles, the rest are never picked.
+pub const MAX: usize = 32;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    Weighted,
+    Bag,
+}
+
+pub struct Selector {
+    /// Remaining in the bag per item, for `Mode::Bag`.
+    bag: [u8; MAX],
+    /// Number of items last time, the bag is emptied if it changes.
+    len: u8,
+    /// The last pick plus one, or zero for none.
+    last: u8,
+}
+
+impl Selector {
+    pub const NEW: Selector = Selector {bag: [0; _], len: 0, last: 0};
+
+    /// Pick an index into `weights`, or None if they are all zero.
+    pub fn choose(&mut self, mode: Mode, weights: &[u8], random: &Random)
+            -> Option<usize> {
+        let weights = &weights[.. weights.len().min(MAX)];
+        let n = weights.len();
+        if n != self.len as usize {
+            self.len = n as u8;
+            self.bag = [0; _];
+            self.last = 0;
+        }
+        let last = (self.last as usize).wrapping_sub(1);
+        let bag = mode == Mode::Bag;
+        if bag && (0 .. n).all(|i| i == last || self.bag[i] == 0) {
+            for (b, &w) in self.bag.iter_mut().zip(weights) {
+                *b = b.saturating_add(w);
+            }
+        }
+        let weight =
+            |i: usize| (if bag {self.bag[i]} else {weights[i]}) as u32;
+
+        let mut skip = last;
+        let mut total: u32 = (0 .. n).filter(|&i| i != skip).map(weight).sum();
+        if total == 0 {
+            // Only the last has any weight.
+            skip = usize::MAX;
+            total = (0 .. n).map(weight).sum();
+            if total == 0 {
+                return None;
+            }
+        }
+        let mut r = random.random_n(total);
+        let pick = (0 .. n).filter(|&i| i != skip).find(|&i| {
+            let w = weight(i);
+            if r < w {true} else {r -= w; false}
+        })?;
+        if bag {
+            self.bag[pick] -= 1;
+        }
+        self.last = pick as u8 + 1;
+        Some(pick)
+    }
+}
+
+#[cfg(test)]
+fn draw(selector: &mut Selector, mode: Mode, weights: &[u8], count: usize)
+        -> Vec<usize> {
+    let random = Random::new(99);
+    (0 .. count).map(|_| selector.choose(mode, weights, &random).unwrap())
+        .collect()
+}
+
+#[test]
+fn weighted() {
+    let mut s = Selector::NEW;
+    let picks = draw(&mut s, Mode::Weighted, &[1, 3, 0, 2], 6000);
+    assert!(picks.windows(2).all(|w| w[0] != w[1]));
+    let mut counts = [0; 4];
+    for &p in &picks {
+        counts[p] += 1;
+    }
+    assert_eq!(counts[2], 0);
+    // Heavier items are picked more.
+    assert!(counts[1] > counts[3] && counts[3] > counts[0], "{counts:?}");
+
+    // A single item repeats, and no weight gives nothing.
+    assert_eq!(draw(&mut s, Mode::Weighted, &[0, 5], 3), [1, 1, 1]);
+    let random = Random::new(1);
+    assert_eq!(s.choose(Mode::Weighted, &[0, 0], &random), None);
+    assert_eq!(s.choose(Mode::Bag, &[], &random), None);
+}
+
+#[test]
+fn bag() {
+    let mut s = Selector::NEW;
+    let picks = draw(&mut s, Mode::Bag, &[1; 7], 70);
+    assert!(picks.windows(2).all(|w| w[0] != w[1]));
+    // Every item in each cycle.
+    for cycle in picks.chunks(7) {
+        let mut seen = [false; 7];
+        for &p in cycle {
+            seen[p] = true;
+        }
+        assert!(seen.iter().all(|&s| s), "{cycle:?}");
+    }
+
+    // Still no repeats, when the weights can't be met without.
+    let mut s = Selector::NEW;
+    let picks = draw(&mut s, Mode::Bag, &[3, 1], 40);
+    assert!(picks.windows(2).all(|w| w[0] != w[1]));
+}
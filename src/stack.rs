@@ -0,0 +1,47 @@
+//! Stack usage.  The free stack is painted at boot, and the high-water mark is
+//! the lowest word no longer holding the paint.  For the worst case by static
+//! analysis, see `blinky-stack` in `tools/`.
+
+use crate::console::Args;
+use crate::cpu::end_of_ram;
+
+const PAINT: u32 = 0xa5c3_5a3c;
+
+/// Space left unpainted below our own frame.
+const MARGIN: usize = 64;
+
+unsafe extern "C" {
+    /// The end of the statics, and the lowest address for the stack.
+    static mut __stack_limit: u8;
+}
+
+/// Paint the free stack, up to just below our own frame.  Called from
+/// `cpu::init`, early, so that little of the stack has been used.
+#[inline(never)]
+pub fn paint() {
+    let marker = 0u8;
+    let top = ((&raw const marker) as usize - MARGIN) & !3;
+    let mut p = (&raw mut __stack_limit) as *mut u32;
+    while (p as usize) < top {
+        // Volatile, so this does not become a memset call.
+        unsafe {p.write_volatile(PAINT)};
+        p = p.wrapping_add(1);
+    }
+}
+
+/// The stack size, and the most used since boot, in bytes.
+pub fn usage() -> (usize, usize) {
+    let limit = (&raw const __stack_limit) as usize;
+    let end = (&raw const end_of_ram) as usize;
+    let mut p = limit as *const u32;
+    while (p as usize) < end && unsafe {p.read_volatile()} == PAINT {
+        p = p.wrapping_add(1);
+    }
+    (end - limit, end - p as usize)
+}
+
+/// Console: `stack`, the high-water mark.
+pub fn cmd_stack(_: &mut Args) {
+    let (size, used) = usage();
+    stm_common::dbgln!("stack {used} of {size} bytes used");
+}
@@ -0,0 +1,144 @@
+//! Internal temperature sensor, and compensation of the LED brightness for
+//! the drift of the LED forward voltage with temperature.
+
+use stm_common::vcell::VCell;
+
+use crate::console::Args;
+use crate::marque::marque_string;
+use crate::pendsv::FIFTH;
+
+/// TS_CAL1, the temperature sensor reading at 30°C and 3.0V.
+const TS_CAL1: *const u16 = 0x1fff75a8 as _;
+
+/// Temperature of TS_CAL1, in tenths of a degree.
+const CAL_TENTHS: i32 = 300;
+
+/// Reference temperature for brightness compensation, in tenths.
+const REF_TENTHS: i32 = 250;
+
+/// Typical LED forward voltage temperature coefficient, in µV/°C (negative).
+const LED_UV_PER_C: u32 = 2000;
+
+/// Filtered temperature.
+#[derive_const(Default)]
+pub struct Temp {
+    /// Tenths of a degree, valid once `valid` is set.
+    tenths: VCell<i32>,
+    valid: VCell<bool>,
+}
+
+pub static TEMP: Temp = Temp::default();
+
+/// Convert a sensor reading to tenths of a degree, given Vdd in mV.
+pub fn tenths(counts: u32, vdd_mv: u32) -> i32 {
+    tenths_cal(counts, unsafe {*TS_CAL1} as u32, vdd_mv)
+}
+
+/// Convert a sensor reading to tenths of a degree, given the calibration and
+/// Vdd in mV.  The sensor slope is 2.5mV/°C, and the calibration is at 3.0V.
+pub const fn tenths_cal(counts: u32, cal: u32, vdd_mv: u32) -> i32 {
+    // The reading scaled as if at 3.0V, in 4096ths of 3.0V.
+    let counts = (counts * vdd_mv) as i32 / 3000;
+    // 3000mV / 4096 / (0.25 mV / tenth) = 750 / 256.
+    CAL_TENTHS + ((counts - cal as i32) * 750 >> 8)
+}
+
+/// Reduce `duty` as the temperature rises above the reference.  `coeff` is
+/// the fractional change in LED current per degree, scaled by 65536, zero for
+/// no compensation.
+pub const fn compensate(duty: u32, tenths: i32, coeff: u16) -> u32 {
+    let change = (tenths - REF_TENTHS) as i64 * coeff as i64 / 10;
+    let duty = duty as i64 - (duty as i64 * change >> 16);
+    if duty < 0 {0} else {duty as u32}
+}
+
+/// The compensation coefficient for LEDs with forward voltage `vf_mv` at
+/// supply `vdd_mv`: the current is proportional to the voltage across the
+/// resistor, and Vf falls with temperature.
+pub const fn coeff(vdd_mv: u32, vf_mv: u32) -> u16 {
+    let coeff = LED_UV_PER_C as u64 * 65536 / ((vdd_mv - vf_mv) as u64 * 1000);
+    if coeff > u16::MAX as u64 {u16::MAX} else {coeff as u16}
+}
+
+impl Temp {
+    pub fn update(&self, tenths: i32) {
+        if self.valid.read() {
+            let old = self.tenths.read();
+            self.tenths.write(old + (tenths - old) / 8);
+        }
+        else {
+            self.tenths.write(tenths);
+            self.valid.write(true);
+        }
+    }
+
+    /// Filtered temperature in tenths of a degree, the reference temperature
+    /// if there is no reading yet.
+    pub fn tenths(&self) -> i32 {
+        if self.valid.read() {self.tenths.read()} else {REF_TENTHS}
+    }
+
+    /// Filtered temperature, rounded to whole degrees.
+    pub fn celsius(&self) -> i32 {
+        let tenths = self.tenths();
+        (tenths + if tenths < 0 {-5} else {5}) / 10
+    }
+}
+
+/// Scroll the temperature, e.g., "21C".
+pub fn show_temperature() {
+    let celsius = TEMP.celsius();
+    let mut buf = [0; 8];
+    let mut len = 0;
+    if celsius < 0 {
+        buf[0] = b'-';
+        len = 1;
+    }
+    len += crate::clock::decimal(&mut buf[len ..], celsius.unsigned_abs());
+    buf[len .. len + 2].copy_from_slice(b"C ");
+    len += 2;
+    crate::chars::map_bytes(&mut buf[.. len]);
+    marque_string(&mut 0, &buf[.. len], FIFTH);
+}
+
+/// Console: `temp`.
+pub fn cmd_temp(_: &mut Args) {
+    let tenths = TEMP.tenths();
+    stm_common::dbgln!("temp {}.{}C comp {}", tenths / 10,
+                       (tenths % 10).unsigned_abs(),
+                       crate::config::get().temp_comp);
+}
+
+/// Console: `tcomp [coeff]`, the brightness compensation in 65536ths per °C.
+pub fn cmd_tcomp(args: &mut Args) {
+    if let Some(coeff) = args.number() {
+        if !(0 ..= 65535).contains(&coeff) || !args.done() {
+            stm_common::dbgln!("tcomp 0..=65535");
+            return;
+        }
+        unsafe {crate::config::CONFIG.as_mut()}.temp_comp = coeff as u16;
+    }
+    stm_common::dbgln!("tcomp {}", crate::config::get().temp_comp);
+}
+
+#[test]
+fn conversion() {
+    // At calibration.
+    assert_eq!(tenths_cal(1000, 1000, 3000), 300);
+    // 2.5mV is 3.41 counts at 3.0V.
+    assert_eq!(tenths_cal(1034, 1000, 3000), 399);
+    assert_eq!(tenths_cal(966, 1000, 3000), 200);
+    // The same voltage at 3.3V gives fewer counts.
+    assert_eq!(tenths_cal(909, 1000, 3300), 297);
+}
+
+#[test]
+fn compensation() {
+    assert_eq!(compensate(1000, REF_TENTHS, 655), 1000);
+    assert_eq!(compensate(1000, 350, 655), 901);
+    assert_eq!(compensate(1000, 150, 655), 1100);
+    assert_eq!(compensate(1000, 350, 0), 1000);
+    // White LEDs at 3V have ≈1%/°C, red much less.
+    assert_eq!(coeff(3000, 2800), 655);
+    assert_eq!(coeff(3000, 1900), 119);
+}
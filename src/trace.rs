@@ -0,0 +1,168 @@
+//! Timing trace of the PWM tick, enabled by `cpu::Config::trace`.
+//!
+//! Each event is timestamped with the TIM3 counter, read first thing in the
+//! handler, and kept as a latency from what triggers it: the update for the
+//! update ISR and the ADC end of sequence, and CC1 for the CC1 ISR and
+//! PendSV.  CC1 is read along with the counter, as PendSV moves it for the
+//! next cycle.  The units are PWM clocks, 4µs whatever the system clock.
+//!
+//! Each event is only recorded from one priority, so there is no locking.
+//! `trace` on the console shows the min, max and a histogram per event, and
+//! the latest few values.
+
+use stm_common::vcell::UCell;
+
+use crate::CONFIG;
+use crate::console::Args;
+use crate::cpu::PWM_CLK;
+use crate::pulse::PWM_DIV;
+
+#[derive(Clone, Copy)]
+pub enum Event {
+    /// TIM3 update ISR, latency from the update.
+    Update,
+    /// TIM3 CC1 ISR, latency from the compare.
+    Cc1,
+    /// PendSV entry and exit, from the compare.
+    PendsvEntry,
+    PendsvExit,
+    /// ADC end of sequence ISR, from the update.
+    AdcEos,
+}
+
+const EVENTS: usize = 5;
+const NAMES: [&str; EVENTS] =
+    ["update", "cc1", "pendsv", "pendsv exit", "eos"];
+
+/// Histogram buckets, by powers of two.
+const BUCKETS: usize = 10;
+/// Number of recent values kept.
+const RECENT: usize = 8;
+
+#[derive(Clone, Copy)]
+pub struct Stats {
+    count: u32,
+    min: u16,
+    max: u16,
+    hist: [u16; BUCKETS],
+    recent: [u16; RECENT],
+}
+
+impl Stats {
+    /// All zeros, so that `STATS` is in the BSS.
+    const NEW: Stats = Stats {
+        count: 0, min: 0, max: 0, hist: [0; _], recent: [0; _]};
+
+    pub fn add(&mut self, latency: u16) {
+        if self.count == 0 || latency < self.min {
+            self.min = latency;
+        }
+        self.recent[self.count as usize % RECENT] = latency;
+        self.count = self.count.wrapping_add(1);
+        self.max = self.max.max(latency);
+        let hist = &mut self.hist[bucket(latency)];
+        *hist = hist.saturating_add(1);
+    }
+}
+
+/// Histogram bucket: 0, 1, 2-3, 4-7, ..., with the last catching the rest.
+pub const fn bucket(latency: u16) -> usize {
+    let b = (u16::BITS - latency.leading_zeros()) as usize;
+    if b < BUCKETS {b} else {BUCKETS - 1}
+}
+
+static STATS: UCell<[Stats; EVENTS]> = UCell::new([Stats::NEW; _]);
+
+/// The TIM3 counter, for an event to be recorded later.
+#[inline(always)]
+pub fn now() -> u16 {
+    if CONFIG.trace {
+        let tim = unsafe {&*stm32g030::TIM3::ptr()};
+        tim.CNT.read().bits() as u16
+    }
+    else {
+        0
+    }
+}
+
+/// The TIM3 CC1 compare value, the reference for the events from CC1, read
+/// along with `now()`.
+#[inline(always)]
+pub fn cc1() -> u16 {
+    if CONFIG.trace {
+        let tim = unsafe {&*stm32g030::TIM3::ptr()};
+        tim.CCR1.read().bits() as u16
+    }
+    else {
+        0
+    }
+}
+
+/// Record an event, timestamped by `now()`, with the latency from
+/// `reference`, 0 for the update, or `cc1()`.
+#[inline(always)]
+pub fn record(event: Event, now: u16, reference: u16) {
+    if !CONFIG.trace {
+        return;
+    }
+    let latency = (now as u32 + PWM_DIV - reference as u32) % PWM_DIV;
+    unsafe {STATS.as_mut()[event as usize].add(latency as u16)};
+}
+
+/// Console: `trace [clear]`.
+pub fn cmd_trace(args: &mut Args) {
+    if !CONFIG.trace {
+        stm_common::dbgln!("trace off, see cpu::Config::trace");
+        return;
+    }
+    match args.word() {
+        b"" => (),
+        b"clear" => {
+            stm_common::interrupt::disable_all();
+            *unsafe {STATS.as_mut()} = [Stats::NEW; _];
+            stm_common::interrupt::enable_all();
+            return;
+        }
+        _ => {
+            stm_common::dbgln!("trace [clear]");
+            return;
+        }
+    }
+    stm_common::interrupt::disable_all();
+    let stats = *STATS.as_ref();
+    stm_common::interrupt::enable_all();
+    stm_common::dbgln!("In PWM clocks of {}µs", 1_000_000 / PWM_CLK);
+    for (name, s) in NAMES.iter().zip(&stats) {
+        if s.count == 0 {
+            stm_common::dbgln!("{name:11} none");
+            continue;
+        }
+        stm_common::dbgln!("{name:11} {} min {} max {} hist {:?} recent {:?}",
+                           s.count, s.min, s.max, s.hist, s.recent);
+    }
+}
+
+impl crate::cpu::Config {
+    /// Trace the PWM tick timing, see `trace`.
+    pub const fn trace(&mut self) -> &mut Self {
+        self.trace = true;
+        self
+    }
+}
+
+#[test]
+fn stats() {
+    assert_eq!(bucket(0), 0);
+    assert_eq!(bucket(1), 1);
+    assert_eq!(bucket(3), 2);
+    assert_eq!(bucket(4), 3);
+    assert_eq!(bucket(u16::MAX), BUCKETS - 1);
+    let mut s = Stats::NEW;
+    for latency in [5, 3, 200, 4] {
+        s.add(latency);
+    }
+    assert_eq!((s.count, s.min, s.max), (4, 3, 200));
+    assert_eq!(s.hist[..4], [0, 0, 1, 2]);
+    assert_eq!(s.hist[8], 1);
+    assert_eq!(s.recent[.. 4], [5, 3, 200, 4]);
+}
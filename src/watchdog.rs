@@ -0,0 +1,148 @@
+//! Independent watchdog.
+//!
+//! The IWDG is fed from the PendSV tick, but only if the application has
+//! shown progress since the last feed, by waking in `pendsv::sleep`.  So a
+//! stopped TIM3, an ISR hogging the CPU, or the application stuck outside of
+//! `sleep`, all end in a reset.
+//!
+//! The default option bytes keep the IWDG running in STOP and STANDBY, which
+//! would turn a scheduled off period into a wake-up every half minute, so
+//! `init` clears IWDG_STOP and IWDG_STDBY to freeze it.  That is a one-off
+//! option byte reload, which resets the device, see `boot::Cause::Option`.
+//! `power::stop` feeds it first, so that the full timeout is left on waking.
+//!
+//! If the option bytes can't be written, the IWDG keeps running in STOP and
+//! STANDBY.  Then `power::stop` switches to the longest timeout, and limits
+//! the sleep to `MAX_STOP`.  After the watchdog reset from
+//! `power::shutdown`, `boot::init` goes straight back to STANDBY.
+//!
+//! Under the debugger, `blinky.jlink` sets DBG_IWDG_STOP so that the watchdog
+//! pauses while the core is halted.
+
+use stm_common::vcell::VCell;
+
+use crate::CONFIG;
+use crate::pendsv::{CYCLES_PER_TICK, SECOND};
+use crate::cpu::PWM_CLK;
+use crate::pulse::PWM_DIV;
+
+/// Fastest LSI, from the datasheet, so that a timeout is never short.
+const LSI_MAX: u32 = 34_000;
+
+/// Length of a PendSV tick in µs.
+const TICK_US: u32 = (CYCLES_PER_TICK as u64 * PWM_DIV as u64
+                      * 1_000_000 / PWM_CLK as u64) as u32;
+
+/// Timeout, in ticks.
+const TIMEOUT_TICKS: u32 = 2 * SECOND;
+
+/// Prescaler (÷32) and reload.
+const PR: u32 = 3;
+const RELOAD: u32 = (TIMEOUT_TICKS as u64 * TICK_US as u64
+                     * LSI_MAX as u64 / 32 / 1_000_000) as u32;
+
+const _: () = assert!(TICK_US == 1_000_000 / SECOND);
+const _: () = assert!(RELOAD <= 0xfff);
+
+/// The longest timeout, prescaler ÷256 and full reload, for STOP when the
+/// watchdog isn't frozen.
+const STOP_PR: u32 = 6;
+const STOP_RELOAD: u32 = 0xfff;
+
+/// Longest STOP when the watchdog isn't frozen, allowing a couple of seconds
+/// for the wake-up.
+pub const MAX_STOP: u32 = (STOP_RELOAD + 1) * 256 / LSI_MAX - 2;
+
+const _: () = assert!(MAX_STOP >= 20 && MAX_STOP <= crate::rtc::MAX_WAKEUP);
+
+/// FLASH_OPTR bits that keep the IWDG running in STOP and STANDBY.
+const IWDG_STOP: u32 = 1 << 17;
+const IWDG_STDBY: u32 = 1 << 18;
+
+/// Set by the application, cleared when the watchdog is fed.
+static ALIVE: VCell<bool> = VCell::new(false);
+
+/// The watchdog is running, and not frozen in STOP and STANDBY.
+static THAWED: VCell<bool> = VCell::new(false);
+
+/// Start the watchdog, first freezing it in STOP and STANDBY if need be.
+pub fn init() {
+    if !CONFIG.watchdog {
+        return;
+    }
+    // This only returns if the options are already clear, or on an error.
+    if let Err(e) = crate::flash::clear_options(IWDG_STOP | IWDG_STDBY) {
+        stm_common::dbgln!("watchdog options {e:?}");
+        THAWED.write(true);
+    }
+    let iwdg = unsafe {&*stm32g030::IWDG::ptr()};
+    iwdg.KR.write(|w| w.bits(0xcccc));
+    configure(PR, RELOAD);
+}
+
+fn configure(pr: u32, reload: u32) {
+    let iwdg = unsafe {&*stm32g030::IWDG::ptr()};
+    iwdg.KR.write(|w| w.bits(0x5555));
+    iwdg.PR.write(|w| w.bits(pr));
+    iwdg.RLR.write(|w| w.bits(reload));
+    // Wait for the LSI domain to take the new values.
+    while iwdg.SR.read().bits() != 0 {
+    }
+    feed();
+}
+
+fn feed() {
+    let iwdg = unsafe {&*stm32g030::IWDG::ptr()};
+    iwdg.KR.write(|w| w.bits(0xaaaa));
+}
+
+/// Application progress, from `pendsv::sleep`.
+pub fn alive() {
+    ALIVE.write(true);
+}
+
+/// Tick progress, from the PendSV tick.  Feed if the application has made
+/// progress too.
+pub fn tick() {
+    if CONFIG.watchdog && ALIVE.read() {
+        ALIVE.write(false);
+        feed();
+    }
+}
+
+/// Switch to the longest timeout, if the watchdog runs in STOP and STANDBY.
+pub fn longest() {
+    if THAWED.read() {
+        configure(STOP_PR, STOP_RELOAD);
+    }
+}
+
+/// Feed before STOP, so that the full timeout is left on waking, and return
+/// how long to stop for.  That is capped at `MAX_STOP` if the watchdog isn't
+/// frozen.
+pub fn stop(seconds: u32) -> u32 {
+    if !CONFIG.watchdog {
+        return seconds;
+    }
+    if !THAWED.read() {
+        feed();
+        return seconds;
+    }
+    longest();
+    seconds.min(MAX_STOP)
+}
+
+/// Back to the running timeout after STOP.
+pub fn wake() {
+    if THAWED.read() {
+        configure(PR, RELOAD);
+    }
+}
+
+impl crate::cpu::Config {
+    /// Enable the independent watchdog.
+    pub const fn watchdog(&mut self) -> &mut Self {
+        self.watchdog = true;
+        self
+    }
+}
@@ -0,0 +1,89 @@
+//! Just enough ELF32 reading for the tools.
+
+pub struct Section {
+    pub name: String,
+    pub kind: u32,
+    pub addr: u32,
+    offset: usize,
+    size: usize,
+    link: usize,
+}
+
+pub struct Symbol {
+    pub name: String,
+    pub value: u32,
+    pub size: u32,
+    pub func: bool,
+}
+
+pub struct Elf<'a> {
+    data: &'a [u8],
+    pub sections: Vec<Section>,
+}
+
+pub fn u16_at(data: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes(data[at .. at + 2].try_into().unwrap())
+}
+
+pub fn u32_at(data: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(data[at .. at + 4].try_into().unwrap())
+}
+
+pub fn c_str(data: &[u8], at: usize) -> String {
+    let end = data[at ..].iter().position(|&b| b == 0).map_or(data.len(),
+                                                              |n| at + n);
+    String::from_utf8_lossy(&data[at .. end]).into_owned()
+}
+
+impl<'a> Elf<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Elf<'a>, String> {
+        if data.get(.. 6) != Some(b"\x7fELF\x01\x01") {
+            return Err("not a 32-bit little endian ELF file".to_string());
+        }
+        let shoff = u32_at(data, 0x20) as usize;
+        let shentsize = u16_at(data, 0x2e) as usize;
+        let shnum = u16_at(data, 0x30) as usize;
+        let shstrndx = u16_at(data, 0x32) as usize;
+        let mut names = Vec::new();
+        let mut sections: Vec<Section> = (0 .. shnum).map(|i| {
+            let at = shoff + i * shentsize;
+            names.push(u32_at(data, at) as usize);
+            Section {
+                name: String::new(), kind: u32_at(data, at + 4),
+                addr: u32_at(data, at + 12),
+                offset: u32_at(data, at + 16) as usize,
+                size: u32_at(data, at + 20) as usize,
+                link: u32_at(data, at + 24) as usize,
+            }
+        }).collect();
+        let mut elf = Elf {data, sections: Vec::new()};
+        let strings = elf.contents(&sections[shstrndx]);
+        for (s, name) in sections.iter_mut().zip(names) {
+            s.name = c_str(strings, name);
+        }
+        elf.sections = sections;
+        Ok(elf)
+    }
+
+    pub fn contents(&self, section: &Section) -> &'a [u8] {
+        &self.data[section.offset .. section.offset + section.size]
+    }
+
+    pub fn section(&self, name: &str) -> Option<&Section> {
+        self.sections.iter().find(|s| s.name == name)
+    }
+
+    pub fn symbols(&self) -> Result<Vec<Symbol>, String> {
+        const SHT_SYMTAB: u32 = 2;
+        const STT_FUNC: u8 = 2;
+        let symtab = self.sections.iter().find(|s| s.kind == SHT_SYMTAB)
+            .ok_or("no symbol table")?;
+        let strings = self.contents(&self.sections[symtab.link]);
+        Ok(self.contents(symtab).chunks_exact(16).map(|sym| Symbol {
+            name: c_str(strings, u32_at(sym, 0) as usize),
+            value: u32_at(sym, 4),
+            size: u32_at(sym, 8),
+            func: sym[12] & 0xf == STT_FUNC,
+        }).collect())
+    }
+}
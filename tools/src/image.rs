@@ -0,0 +1,360 @@
+//! Build, inspect and diff images of the reserved config flash pages.
+//!
+//! The record format comes straight from the firmware source, so the two
+//! cannot drift.
+//!
+//! ```text
+//! blinky-image create <spec> <image.bin>
+//! blinky-image decode <image.bin>
+//! blinky-image validate <image.bin>
+//! blinky-image diff <a.bin> <b.bin>
+//! ```
+//!
+//! A spec is text, one item per line, `#` for comments.  Config fields are
+//! `<name> <value>`, with `led_corr` taking 36 values.  Messages are
+//! `msg main|ex <weight> <text>`.  Missing config fields are zero, except
+//! `led_corr`, which is full brightness.  `decode` prints a spec, so that an
+//! image read back from a board can be edited and recreated.  The boot
+//! counts, see `boot`, are only shown, as a comment.
+//!
+//! The image covers both pages, and is loaded with the J-Link `loadfile
+//! <image.bin> 0x08007000`.
+
+#![feature(const_default, const_trait_impl, derive_const)]
+
+use std::fmt::Write;
+use std::process::ExitCode;
+
+#[allow(dead_code, clippy::precedence, clippy::redundant_static_lifetimes)]
+#[path = "../../src/chars.rs"]
+mod chars;
+#[allow(dead_code)]
+#[macro_use]
+#[path = "../../src/record.rs"]
+mod record;
+
+use record::*;
+
+type Result<T> = std::result::Result<T, String>;
+
+/// A config field as text.
+trait Text {
+    fn parse(&mut self, value: &str) -> Option<()>;
+    fn show(&self) -> String;
+}
+
+macro_rules! text {($($t:ty)*) => {$(
+    impl Text for $t {
+        fn parse(&mut self, value: &str) -> Option<()> {
+            *self = value.parse().ok()?;
+            Some(())
+        }
+        fn show(&self) -> String {self.to_string()}
+    }
+)*}}
+
+text!(u8 u16 u32 i32);
+
+impl Text for LedCorr {
+    fn parse(&mut self, value: &str) -> Option<()> {
+        let values: Vec<u8> = value.split_whitespace()
+            .map(|v| v.parse().ok()).collect::<Option<_>>()?;
+        self.0 = values.try_into().ok()?;
+        Some(())
+    }
+    fn show(&self) -> String {
+        self.0.map(|c| c.to_string()).join(" ")
+    }
+}
+
+/// An image of the reserved pages.
+struct Image(Vec<u8>);
+
+impl Store for Image {
+    fn page(&self, page: usize) -> &[u8] {
+        &self.0[page * PAGE_SIZE .. (page + 1) * PAGE_SIZE]
+    }
+    fn erase(&mut self, page: usize) -> std::result::Result<(), Error> {
+        self.0[page * PAGE_SIZE .. (page + 1) * PAGE_SIZE].fill(0xff);
+        Ok(())
+    }
+    fn program(&mut self, page: usize, offset: usize, data: &[u8])
+        -> std::result::Result<(), Error> {
+        let at = page * PAGE_SIZE + offset;
+        self.0[at .. at + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+}
+
+/// The decoded contents of an image.
+#[derive(Default)]
+struct Contents {
+    config: Option<(u8, Config)>,
+    messages: Vec<(bool, u8, Vec<u8>)>,
+    /// Boot counts, written by the firmware, and only shown.
+    boots: Option<BootCounts>,
+}
+
+impl Image {
+    fn new() -> Image {Image(vec![0xff; PAGE_SIZE * PAGES])}
+
+    fn read(path: &str) -> Result<Image> {
+        let data = std::fs::read(path).map_err(|e| format!("{path}: {e}"))?;
+        if data.len() != PAGE_SIZE * PAGES {
+            return Err(format!("{path}: {} bytes, expected {}",
+                               data.len(), PAGE_SIZE * PAGES));
+        }
+        Ok(Image(data))
+    }
+
+    fn contents(&self) -> Contents {
+        let page = self.page(active(self));
+        let config = latest(page, KIND_CONFIG).map(|r| {
+            let mut config = Config::default();
+            config.decode(r.payload);
+            (r.header.version, config)
+        });
+        let messages = latest(page, KIND_MESSAGES).map_or(vec![], |r| {
+            Messages(r.payload).map(
+                |m| (m.main, m.weight, m.text.to_vec())).collect()
+        });
+        let boots = latest(page, KIND_BOOT)
+            .map(|r| BootCounts::decode(r.payload));
+        Contents {config, messages, boots}
+    }
+}
+
+impl Contents {
+    fn parse(spec: &str) -> Result<Contents> {
+        let mut contents = Contents::default();
+        for (n, line) in spec.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            let error = |what: &str| format!("line {}: {what}: {line}", n + 1);
+            let Some((name, value)) = line.split_once(' ') else {
+                if line.is_empty() {continue}
+                return Err(error("no value"));
+            };
+            let value = value.trim();
+            if name == "msg" {
+                let mut words = value.splitn(3, ' ');
+                let main = match words.next() {
+                    Some("main") => true,
+                    Some("ex") => false,
+                    _ => return Err(error("expected main or ex")),
+                };
+                let weight = words.next().and_then(|w| w.parse().ok())
+                    .ok_or_else(|| error("bad weight"))?;
+                let text = words.next().unwrap_or("").as_bytes().to_vec();
+                contents.messages.push((main, weight, text));
+                continue;
+            }
+            let (_, config) = contents.config.get_or_insert(
+                (CONFIG_VERSION, Config::default()));
+            let mut found = None;
+            let mut set = |field: &str, f: &mut dyn Text| if field == name {
+                found = Some(f.parse(value));
+            };
+            config_fields!(config, set);
+            match found {
+                None => return Err(error("unknown field")),
+                Some(None) => return Err(error("bad value")),
+                Some(Some(())) => (),
+            }
+        }
+        Ok(contents)
+    }
+
+    /// The contents as a spec.
+    fn spec(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        if let Some((version, config)) = &self.config {
+            lines.push(format!("# config version {version}"));
+            let mut config = *config;
+            let mut show = |name: &str, f: &mut dyn Text|
+                lines.push(format!("{name} {}", f.show()));
+            config_fields!(&mut config, show);
+        }
+        for (main, weight, text) in &self.messages {
+            lines.push(format!("msg {} {weight} {}",
+                               if *main {"main"} else {"ex"},
+                               String::from_utf8_lossy(text)));
+        }
+        if let Some(boots) = &self.boots {
+            let counts: Vec<String> = CAUSES.iter().zip(boots.0)
+                .map(|(name, count)| format!("{name} {count}")).collect();
+            lines.push(format!("# boots {}", counts.join(" ")));
+        }
+        lines
+    }
+
+    fn image(&self) -> Result<Image> {
+        let mut image = Image::new();
+        if let Some((_, config)) = &self.config {
+            let mut buf = [0; CONFIG_LEN];
+            let len = config.encode(&mut buf);
+            append(&mut image, KIND_CONFIG, CONFIG_VERSION, &buf[.. len])
+                .map_err(|e| format!("config: {e:?}"))?;
+        }
+        if !self.messages.is_empty() {
+            let mut buf = [0; MESSAGES_LEN];
+            let mut len = 0;
+            for (main, weight, text) in &self.messages {
+                let message = Message {main: *main, weight: *weight, text};
+                if !message.encode(&mut buf, &mut len) {
+                    return Err(format!("messages: more than {MESSAGES_LEN} \
+                                        bytes"));
+                }
+            }
+            append(&mut image, KIND_MESSAGES, MESSAGES_VERSION, &buf[.. len])
+                .map_err(|e| format!("messages: {e:?}"))?;
+        }
+        Ok(image)
+    }
+
+    /// Problems that would make the firmware ignore or mangle the contents.
+    fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if let Some((_, config)) = &self.config && !config.is_valid() {
+            problems.push("config fails the sanity check, and will be \
+                           ignored".to_string());
+        }
+        for (i, (_, weight, text)) in self.messages.iter().enumerate() {
+            if *weight == 0 {
+                problems.push(format!("msg {i}: zero weight"));
+            }
+            if text.is_empty() {
+                problems.push(format!("msg {i}: empty"));
+            }
+            for &c in text {
+                if chars::find_char(c).is_none() {
+                    problems.push(format!("msg {i}: {:?} is not in the font",
+                                          c as char));
+                }
+            }
+        }
+        problems
+    }
+}
+
+fn validate(image: &Image) -> Vec<String> {
+    let mut problems = Vec::new();
+    for page in 0 .. PAGES {
+        let mut records = Records::new(image.page(page));
+        let count = records.by_ref().count();
+        let mut report = format!("page {page}: {count} records");
+        if records.skipped != 0 {
+            write!(report, ", {} with bad CRCs", records.skipped).unwrap();
+        }
+        if records.end == PAGE_SIZE {
+            report.push_str(", full");
+        }
+        println!("{report}");
+        let erased =
+            image.page(page)[records.end ..].iter().all(|&b| b == 0xff);
+        if !erased {
+            problems.push(format!("page {page}: garbage after the records"));
+        }
+    }
+    println!("active page {}", active(image));
+    problems.extend(image.contents().problems());
+    problems
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match run(&args) {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(args: &[&str]) -> Result<bool> {
+    match args {
+        ["create", spec, out] => {
+            let text = std::fs::read_to_string(spec)
+                .map_err(|e| format!("{spec}: {e}"))?;
+            let contents = Contents::parse(&text)?;
+            let problems = contents.problems();
+            for p in &problems {
+                eprintln!("{p}");
+            }
+            if !problems.is_empty() {
+                return Ok(false);
+            }
+            std::fs::write(out, contents.image()?.0)
+                .map_err(|e| format!("{out}: {e}"))?;
+            Ok(true)
+        }
+        ["decode", path] => {
+            for line in Image::read(path)?.contents().spec() {
+                println!("{line}");
+            }
+            Ok(true)
+        }
+        ["validate", path] => {
+            let problems = validate(&Image::read(path)?);
+            for p in &problems {
+                println!("{p}");
+            }
+            Ok(problems.is_empty())
+        }
+        ["diff", a, b] => {
+            let a = Image::read(a)?.contents().spec();
+            let b = Image::read(b)?.contents().spec();
+            for line in a.iter().filter(|l| !b.contains(l)) {
+                println!("- {line}");
+            }
+            for line in b.iter().filter(|l| !a.contains(l)) {
+                println!("+ {line}");
+            }
+            Ok(a == b)
+        }
+        _ => Err("usage: blinky-image create <spec> <image.bin> | decode \
+                  <image.bin> | validate <image.bin> | diff <a.bin> <b.bin>"
+                 .to_string()),
+    }
+}
+
+#[cfg(test)]
+const SPEC: &str = "\
+# config version 3
+vf_mv 2750
+pwm_scale 206250
+led_ohms 330
+source_ohms 0
+current_cap 0
+lsi_trim -120
+on_start 1020
+on_minutes 360
+season 0
+low_mv 2400
+critical_mv 2100
+temp_comp 0
+vref_cal 0
+led_corr 255 255 255 255 255 255 255 255 255 255 255 255 255 255 255 255 \
+255 255 255 255 255 255 255 255 255 255 255 255 255 255 255 255 255 255 255 200
+msg main 1 HELLO WORLD
+msg ex 10 PEACE";
+
+#[test]
+fn round_trip() {
+    let contents = Contents::parse(SPEC).unwrap();
+    assert!(contents.problems().is_empty());
+    let image = contents.image().unwrap();
+    assert!(validate(&image).is_empty());
+    assert_eq!(image.contents().spec().join("\n"), SPEC);
+}
+
+#[test]
+fn problems() {
+    let contents = Contents::parse("led_ohms 0\nmsg ex 0 lower").unwrap();
+    assert_eq!(contents.problems().len(), 1 + 1 + 5);
+    assert!(Contents::parse("bogus 1").is_err());
+    assert!(Contents::parse("led_corr 1 2 3").is_err());
+    assert!(Contents::parse("msg both 1 X").is_err());
+}
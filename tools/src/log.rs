@@ -0,0 +1,199 @@
+//! Decode the deferred log, see `log.rs` in the firmware.
+//!
+//! ```text
+//! blinky-log <blinky.elf> [<capture>]
+//! ```
+//!
+//! Reads a capture of the debug UART, from the file or stdin, and prints it
+//! with the log frames turned back into text, using the format strings from
+//! the `.logstr` section of the ELF file.  Everything else is passed through.
+//!
+//! A frame is `\x1e`, a length byte, then that many bytes of frame.
+
+use std::io::{Read, Write};
+use std::process::ExitCode;
+
+#[cfg(test)]
+#[allow(dead_code, clippy::precedence, clippy::redundant_static_lifetimes)]
+#[path = "../../src/chars.rs"]
+mod chars;
+#[allow(dead_code)]
+mod elf;
+#[allow(clippy::needless_range_loop, clippy::precedence)]
+#[path = "../../src/pendsv/text.rs"]
+mod text;
+
+type Result<T> = std::result::Result<T, String>;
+
+fn leb128(data: &mut &[u8]) -> Option<u64> {
+    let mut v = 0;
+    let mut shift = 0;
+    loop {
+        let (&b, rest) = data.split_first()?;
+        *data = rest;
+        v |= (b as u64 & 0x7f).checked_shl(shift)?;
+        shift += 7;
+        if b & 0x80 == 0 {
+            return Some(v);
+        }
+    }
+}
+
+/// An argument, as an i64 if signed.
+#[derive(Clone, Copy)]
+enum Arg {
+    Unsigned(u64),
+    Signed(i64),
+}
+
+/// Split a format string into literal text and placeholder specs.
+fn pieces(fmt: &str) -> Vec<(String, Option<&str>)> {
+    let mut pieces = Vec::new();
+    let mut text = String::new();
+    let mut rest = fmt;
+    while let Some(c) = rest.chars().next() {
+        if rest.starts_with("{{") || rest.starts_with("}}") {
+            text.push(c);
+            rest = &rest[2 ..];
+        }
+        else if c == '{' && let Some(end) = rest.find('}') {
+            pieces.push((std::mem::take(&mut text), Some(&rest[1 .. end])));
+            rest = &rest[end + 1 ..];
+        }
+        else {
+            text.push(c);
+            rest = &rest[c.len_utf8() ..];
+        }
+    }
+    pieces.push((text, None));
+    pieces
+}
+
+fn show(spec: &str, arg: Arg) -> String {
+    match (spec, arg) {
+        (":x", Arg::Unsigned(v)) => format!("{v:x}"),
+        (":#x", Arg::Unsigned(v)) => format!("{v:#x}"),
+        (":x", Arg::Signed(v)) => format!("{v:x}"),
+        (":#x", Arg::Signed(v)) => format!("{v:#x}"),
+        (":blocks", Arg::Unsigned(v)) => {
+            let b = text::blocks(v);
+            format!("{}{}{}\n{}{}{}", b[0], b[1], b[2], b[3], b[4], b[5])
+        }
+        (_, Arg::Unsigned(v)) => v.to_string(),
+        (_, Arg::Signed(v)) => v.to_string(),
+    }
+}
+
+/// Decode a frame, given the `.logstr` contents.
+fn decode(strings: &[u8], frame: &[u8]) -> Option<String> {
+    let mut data = frame;
+    let id = leb128(&mut data)? as usize;
+    if id >= strings.len() {
+        return None;
+    }
+    let fmt = elf::c_str(strings, id);
+    let pieces = pieces(&fmt);
+    let n = pieces.len() - 1;
+    let signed = if n > 0 {
+        let (&signed, rest) = data.split_first()?;
+        data = rest;
+        signed
+    }
+    else {
+        0
+    };
+    let mut out = String::new();
+    for (i, (text, spec)) in pieces.into_iter().enumerate() {
+        out.push_str(&text);
+        let Some(spec) = spec else {break};
+        let v = leb128(&mut data)?;
+        let arg = if signed >> i & 1 != 0 {
+            Arg::Signed((v >> 1) as i64 ^ -((v & 1) as i64))
+        }
+        else {
+            Arg::Unsigned(v)
+        };
+        out.push_str(&show(spec, arg));
+    }
+    if data.is_empty() {Some(out)} else {None}
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let (elf, capture) = match &args[..] {
+        [elf] => (elf, None),
+        [elf, capture] => (elf, Some(capture)),
+        _ => {
+            eprintln!("usage: blinky-log <blinky.elf> [<capture>]");
+            return ExitCode::FAILURE;
+        }
+    };
+    match run(elf, capture) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(path: &str, capture: Option<&String>) -> Result<()> {
+    let data = std::fs::read(path).map_err(|e| format!("{path}: {e}"))?;
+    let elf = elf::Elf::parse(&data).map_err(|e| format!("{path}: {e}"))?;
+    let strings = elf.section(".logstr").map_or(&[][..], |s| elf.contents(s));
+
+    let mut input = Vec::new();
+    match capture {
+        Some(capture) => input = std::fs::read(capture)
+            .map_err(|e| format!("{capture}: {e}"))?,
+        None => {
+            std::io::stdin().read_to_end(&mut input)
+                .map_err(|e| e.to_string())?;
+        }
+    }
+    let mut out = std::io::stdout().lock();
+    out.write_all(&translate(strings, &input)).map_err(|e| e.to_string())
+}
+
+/// Replace the frames in a capture with their text, on a line each.
+fn translate(strings: &[u8], input: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut rest = input;
+    while let Some((&c, tail)) = rest.split_first() {
+        rest = tail;
+        if c != 0x1e {
+            out.push(c);
+            continue;
+        }
+        let decoded = rest.split_first().and_then(|(&len, tail)| {
+            let frame = tail.get(.. len as usize)?;
+            Some((decode(strings, frame)?, &tail[len as usize ..]))
+        });
+        match decoded {
+            Some((text, tail)) => {
+                if out.last().is_some_and(|&c| c != b'\n') {
+                    out.push(b'\n');
+                }
+                out.extend_from_slice(text.as_bytes());
+                out.push(b'\n');
+                rest = tail;
+            }
+            None => out.push(c),
+        }
+    }
+    out
+}
+
+#[test]
+fn frames() {
+    let strings = b"Wakes\0ADC {} {} {:#x}\0{:blocks}\0";
+    assert_eq!(decode(strings, &[0]).unwrap(), "Wakes");
+    // 5u8, -2i32, 0x10u32.
+    assert_eq!(decode(strings, &[6, 0b010, 5, 3, 0x10]).unwrap(),
+               "ADC 5 -2 0x10");
+    assert!(decode(strings, &[6, 0, 5]).is_none());
+    assert_eq!(decode(strings, &[22, 0, 0]).unwrap(), "   \n   ");
+    assert_eq!(pieces("{{}} {}").len(), 2);
+    assert_eq!(translate(strings, b"Boot\n\x1e\x01\x00ok\x1e\x09"),
+               b"Boot\nWakes\nok\x1e\x09");
+}
@@ -0,0 +1,340 @@
+//! Worst case stack depth, from the `.stack_sizes` section of `blinky.elf`
+//! and a call graph found by scanning the code for BL and branches.
+//!
+//! ```text
+//! blinky-stack [-v] <blinky.elf>
+//! ```
+//!
+//! Each interrupt priority can preempt those below it, so the worst case is
+//! the deepest entry point at each priority, plus an exception frame for
+//! each preemption.  This is checked against the space between the statics
+//! and the end of RAM.
+//!
+//! Indirect calls (BLX) cannot be followed, and functions without a
+//! `.stack_sizes` entry (assembler, compiler builtins) count as zero; both
+//! are flagged.  Literal pools may add false edges, which only makes the
+//! answer more pessimistic.
+
+use std::collections::HashMap;
+use std::process::ExitCode;
+
+mod elf;
+
+use elf::{Elf, u16_at, u32_at};
+
+type Result<T> = std::result::Result<T, String>;
+
+/// Entry points by priority, highest first.  A name matches the end of the
+/// demangled path.  See `cpu::PRIO_*`.
+const LEVELS: &[(&str, &[&str])] = &[
+    ("HardFault", &["fault::fault_entry"]),
+    ("PRIO_PULSE", &["pulse::isr"]),
+    ("PRIO_DEBUG", &["debug::debug_isr"]),
+    ("PRIO_PENDSV", &["pendsv::pendsv_handler", "adc::isr", "rtc::isr"]),
+    ("thread", &["main"]),
+];
+
+/// Stacked by each exception entry, plus the alignment padding.
+const FRAME: u32 = 36;
+
+struct Function {
+    name: String,
+    size: u32,
+    /// From `.stack_sizes`.
+    stack: Option<u32>,
+    calls: Vec<u32>,
+    indirect: bool,
+}
+
+/// Depth of a function, with the flags from its callees.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Depth {
+    bytes: u32,
+    /// Callee on the deepest path.
+    via: Option<u32>,
+    /// Something below is indirect, recursive or has no stack size.
+    unsure: bool,
+}
+
+struct Graph {
+    functions: HashMap<u32, Function>,
+    depths: HashMap<u32, Option<Depth>>,
+}
+
+/// Legacy Rust demangling, without the hash, or the name as is.
+fn demangle(name: &str) -> String {
+    let Some(mut rest) = name.strip_prefix("_ZN") else {
+        return name.to_string();
+    };
+    let mut parts = Vec::new();
+    while let Some(digits) = rest.find(|c: char| !c.is_ascii_digit())
+        && digits > 0 {
+        let len: usize = rest[.. digits].parse().unwrap();
+        let Some(part) = rest.get(digits .. digits + len) else {break};
+        parts.push(part);
+        rest = &rest[digits + len ..];
+    }
+    if let Some(last) = parts.last()
+        && last.len() == 17 && last.starts_with('h')
+        && last[1 ..].chars().all(|c| c.is_ascii_hexdigit()) {
+        parts.pop();
+    }
+    parts.join("::")
+}
+
+fn matches(path: &str, name: &str) -> bool {
+    path == name || path.ends_with(&format!("::{name}"))
+}
+
+/// Target of a 32-bit BL, at `pc`.
+fn bl_target(pc: u32, hi: u16, lo: u16) -> u32 {
+    let s = (hi as u32 >> 10) & 1;
+    let i1 = !((lo as u32 >> 13) ^ s) & 1;
+    let i2 = !((lo as u32 >> 11) ^ s) & 1;
+    let imm = s << 24 | i1 << 23 | i2 << 22 | (hi as u32 & 0x3ff) << 12
+        | (lo as u32 & 0x7ff) << 1;
+    // Sign extend from 25 bits.
+    let imm = ((imm << 7) as i32 >> 7) as u32;
+    pc.wrapping_add(4).wrapping_add(imm)
+}
+
+/// Calls from code at `start`, and whether any are indirect.  Branches out
+/// of the function are tail calls.
+fn scan(code: &[u8], start: u32) -> (Vec<u32>, bool) {
+    let end = start + code.len() as u32;
+    let mut calls = Vec::new();
+    let mut indirect = false;
+    let mut i = 0;
+    while i + 2 <= code.len() {
+        let pc = start + i as u32;
+        let insn = u16_at(code, i);
+        i += 2;
+        let target = if insn & 0xf800 == 0xf000 && i + 2 <= code.len()
+            && u16_at(code, i) & 0xd000 == 0xd000 {
+            let lo = u16_at(code, i);
+            i += 2;
+            bl_target(pc, insn, lo)
+        }
+        else if insn & 0xff87 == 0x4780 {
+            indirect = true;
+            continue;
+        }
+        else if insn & 0xf800 == 0xe000 {
+            // B, 11 bit offset.
+            let imm = ((insn as u32) << 21) as i32 >> 20;
+            pc.wrapping_add(4).wrapping_add(imm as u32)
+        }
+        else if insn & 0xf000 == 0xd000 && insn & 0x0e00 != 0x0e00 {
+            // B<cond>, 8 bit offset.
+            let imm = ((insn as u32) << 24) as i32 >> 23;
+            pc.wrapping_add(4).wrapping_add(imm as u32)
+        }
+        else {
+            continue;
+        };
+        if !(start .. end).contains(&target) && !calls.contains(&target) {
+            calls.push(target);
+        }
+    }
+    (calls, indirect)
+}
+
+impl Graph {
+    fn read(elf: &Elf) -> Result<(Graph, HashMap<String, u32>)> {
+        let text = elf.section(".text").ok_or("no .text")?;
+        let code = elf.contents(text);
+        let mut functions = HashMap::new();
+        let mut symbols = HashMap::new();
+        for sym in elf.symbols()? {
+            if !sym.func {
+                symbols.insert(sym.name, sym.value);
+                continue;
+            }
+            let addr = sym.value & !1;
+            let code = (addr as usize).checked_sub(text.addr as usize)
+                .and_then(|at| code.get(at .. at + sym.size as usize))
+                .unwrap_or(&[]);
+            let (calls, indirect) = scan(code, addr);
+            functions.insert(addr, Function {
+                name: demangle(&sym.name), size: sym.size, stack: None, calls,
+                indirect});
+        }
+
+        // Address and ULEB128 stack size pairs.
+        let sizes = elf.section(".stack_sizes").ok_or("no .stack_sizes")?;
+        let mut sizes = elf.contents(sizes);
+        while sizes.len() >= 5 {
+            let addr = u32_at(sizes, 0) & !1;
+            let mut stack = 0;
+            let mut shift = 0;
+            let mut n = 4;
+            loop {
+                let b = *sizes.get(n).ok_or("truncated .stack_sizes")?;
+                stack |= (b as u32 & 0x7f) << shift;
+                shift += 7;
+                n += 1;
+                if b & 0x80 == 0 {
+                    break;
+                }
+            }
+            if let Some(f) = functions.get_mut(&addr) {
+                f.stack = Some(stack);
+            }
+            sizes = &sizes[n ..];
+        }
+        Ok((Graph {functions, depths: HashMap::new()}, symbols))
+    }
+
+    /// Worst case depth below `addr`.  Recursion gives `unsure`.
+    fn depth(&mut self, addr: u32) -> Depth {
+        match self.depths.get(&addr) {
+            Some(Some(depth)) => return *depth,
+            Some(None) => return Depth {bytes: 0, via: None, unsure: true},
+            None => (),
+        }
+        let Some(f) = self.functions.get(&addr) else {
+            // Not a function we know, maybe a branch into the middle of one.
+            return Depth {bytes: 0, via: None, unsure: true};
+        };
+        let (stack, calls) = (f.stack, f.calls.clone());
+        let mut depth = Depth {
+            bytes: 0, via: None,
+            unsure: stack.is_none() || f.indirect || f.size == 0};
+        self.depths.insert(addr, None);
+        for call in calls {
+            let below = self.depth(call);
+            depth.unsure |= below.unsure;
+            if depth.via.is_none() || below.bytes > depth.bytes {
+                depth.bytes = below.bytes;
+                depth.via = Some(call);
+            }
+        }
+        depth.bytes += stack.unwrap_or(0);
+        self.depths.insert(addr, Some(depth));
+        depth
+    }
+
+    fn name(&self, addr: u32) -> String {
+        self.functions.get(&addr).map_or_else(|| format!("{addr:#010x}"),
+                                              |f| f.name.clone())
+    }
+
+    /// The deepest path from `addr`.
+    fn path(&mut self, addr: u32) -> Vec<String> {
+        let mut path = Vec::new();
+        let mut at = Some(addr);
+        while let Some(addr) = at && path.len() < 64 {
+            let depth = self.depth(addr);
+            let stack = self.functions.get(&addr).and_then(|f| f.stack);
+            let stack = stack.map_or("?".to_string(), |s| s.to_string());
+            path.push(format!("{stack:>5} {}", self.name(addr)));
+            at = depth.via;
+        }
+        path
+    }
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let (verbose, path) = match args.iter().map(String::as_str)
+        .collect::<Vec<_>>()[..] {
+        ["-v", path] => (true, path),
+        [path] => (false, path),
+        _ => {
+            eprintln!("usage: blinky-stack [-v] <blinky.elf>");
+            return ExitCode::FAILURE;
+        }
+    };
+    match run(path, verbose) {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(e) => {
+            eprintln!("{path}: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(path: &str, verbose: bool) -> Result<bool> {
+    let data = std::fs::read(path).map_err(|e| e.to_string())?;
+    let (mut graph, symbols) = Graph::read(&Elf::parse(&data)?)?;
+    let symbol = |name: &str| symbols.get(name).copied()
+        .ok_or(format!("no symbol {name}"));
+    let available = symbol("end_of_ram")? - symbol("__stack_limit")?;
+
+    let mut total = 0;
+    for (level, (prio, entries)) in LEVELS.iter().enumerate() {
+        let mut worst: Option<(u32, Depth)> = None;
+        let mut addrs: Vec<u32> = graph.functions.iter()
+            .filter(|(_, f)| entries.iter().any(|e| matches(&f.name, e)))
+            .map(|(&addr, _)| addr).collect();
+        addrs.sort();
+        for addr in addrs {
+            let depth = graph.depth(addr);
+            println!("{prio:12} {:>5}{} {}", depth.bytes,
+                     if depth.unsure {"?"} else {" "}, graph.name(addr));
+            if worst.is_none_or(|(_, w)| depth.bytes > w.bytes) {
+                worst = Some((addr, depth));
+            }
+        }
+        let Some((addr, depth)) = worst else {
+            println!("{prio:12} no entry point found");
+            continue;
+        };
+        total += depth.bytes;
+        // Everything but the thread level is entered by an exception.
+        if level + 1 < LEVELS.len() {
+            total += FRAME;
+        }
+        if verbose {
+            for line in graph.path(addr) {
+                println!("             {line}");
+            }
+        }
+    }
+    println!("worst case {total} of {available} bytes");
+    Ok(total <= available)
+}
+
+#[test]
+fn decode() {
+    assert_eq!(demangle("_ZN6blinky5pulse3isr17h0123456789abcdefE"),
+               "blinky::pulse::isr");
+    assert_eq!(demangle("main"), "main");
+    assert!(matches("blinky::pulse::isr", "pulse::isr"));
+    assert!(!matches("blinky::pulse::isr", "e::isr"));
+
+    // bl 0x08000200 at 0x08000100, and back.
+    assert_eq!(bl_target(0x0800_0100, 0xf000, 0xf87e), 0x0800_0200);
+    assert_eq!(bl_target(0x0800_0200, 0xf7ff, 0xff7e), 0x0800_0100);
+}
+
+#[test]
+fn depths() {
+    // A calls B and then tail calls C, C calls B, B calls via a register.
+    let a = [0x00, 0xf0, 0x0e, 0xf8, 0x05, 0xe0];      // bl 0x120; b 0x112
+    let b = [0x98, 0x47];                               // blx r3
+    let c = [0x00, 0xf0, 0x05, 0xf8];                   // bl 0x120
+    assert_eq!(scan(&a, 0x100), (vec![0x120, 0x112], false));
+    assert_eq!(scan(&b, 0x120), (vec![], true));
+    assert_eq!(scan(&c, 0x112), (vec![0x120], false));
+
+    let function = |name: &str, stack, calls: Vec<u32>, indirect| Function {
+        name: name.to_string(), size: 4, stack: Some(stack), calls, indirect};
+    let mut graph = Graph {
+        functions: HashMap::from([
+            (0x100, function("a", 16, vec![0x120, 0x112], false)),
+            (0x112, function("c", 24, vec![0x120], false)),
+            (0x120, function("b", 8, vec![], false)),
+        ]),
+        depths: HashMap::new(),
+    };
+    assert_eq!(graph.depth(0x100),
+               Depth {bytes: 16 + 24 + 8, via: Some(0x112), unsure: false});
+    assert_eq!(graph.path(0x100).len(), 3);
+
+    // Recursion is unsure.
+    graph.functions.get_mut(&0x120).unwrap().calls.push(0x100);
+    graph.depths.clear();
+    assert!(graph.depth(0x100).unsure);
+}